@@ -0,0 +1,71 @@
+use csv::Reader as CsvReader;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::error::{Error, Result};
+
+/// Row of the blocklist and allowlist files.
+#[derive(Debug, Deserialize)]
+struct Entry {
+    client: u16,
+}
+
+/// Access decides which clients may have their records processed.
+#[derive(Debug, Default)]
+pub(crate) struct Access {
+    blocked: HashSet<u16>,
+    // When set, only these clients are processed.
+    allowed: Option<HashSet<u16>>,
+}
+
+impl Access {
+    pub(crate) fn from_paths(blocklist: Option<&str>, allowlist: Option<&str>) -> Result<Self> {
+        let mut access = Self::default();
+        if let Some(file) = blocklist {
+            for client in clients_from_path(file)? {
+                access.block(client);
+            }
+        }
+        if let Some(file) = allowlist {
+            // An empty allowlist still rejects every client.
+            access.allowed = Some(HashSet::new());
+            for client in clients_from_path(file)? {
+                access.allow(client);
+            }
+        }
+        Ok(access)
+    }
+
+    pub(crate) fn block(&mut self, client: u16) {
+        self.blocked.insert(client);
+    }
+
+    pub(crate) fn allow(&mut self, client: u16) {
+        self.allowed.get_or_insert_with(HashSet::new).insert(client);
+    }
+
+    pub(crate) fn blocked(&self) -> impl Iterator<Item = u16> + '_ {
+        self.blocked.iter().copied()
+    }
+
+    /// Checks that a client may transact.
+    pub(crate) fn check(&self, client: u16) -> Result<()> {
+        if self.blocked.contains(&client) {
+            return Err(Error::BlockedClient(client));
+        }
+        match &self.allowed {
+            Some(allowed) if !allowed.contains(&client) => Err(Error::ClientNotAllowed(client)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn clients_from_path(file: &str) -> Result<Vec<u16>> {
+    let mut rdr = CsvReader::from_path(file).map_err(|_| Error::InvalidData)?;
+    let mut clients = Vec::new();
+    for result in rdr.deserialize() {
+        let entry: Entry = result.map_err(|_| Error::InvalidData)?;
+        clients.push(entry.client);
+    }
+    Ok(clients)
+}
@@ -1,90 +1,249 @@
+use csv::Reader as CsvReader;
 use rust_decimal::Decimal;
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
 
+use crate::audit::AuditEntry;
+use crate::clock::{Moment, Window};
 use crate::error::{Error, Result};
+use crate::fees::{FeePosting, FeeType, Schedule, DEFAULT_HOUSE_ACCOUNT};
+use crate::ledger::{self, Book, Entry, Posting};
+use crate::limits::CreditLimits;
+use crate::statement::Event;
+use crate::store::{MemoryStore, Store, Visit};
 
-/// Account is responsible for updating values on account.
-#[derive(Debug, Serialize, Default, Clone)]
-pub(crate) struct Account {
-    client: u16,
+/// Currency of records that don't specify one.
+pub(crate) const DEFAULT_CURRENCY: &str = "USD";
+
+/// Balance of an account in a single currency.
+/// It only changes by postings to the books of the account.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub(crate) struct Balance {
     available: Decimal,
     held: Decimal,
+    // Deposited funds that are not available yet.
+    uncleared: Decimal,
     total: Decimal,
-    locked: bool,
+}
+
+impl Balance {
+    fn post(&mut self, book: Book, amount: Decimal) -> Result<()> {
+        let bucket = match book {
+            Book::Available(_) => &mut self.available,
+            Book::Held(_) => &mut self.held,
+            Book::Uncleared(_) => &mut self.uncleared,
+            _ => return Ok(()),
+        };
+        *bucket = bucket.checked_add(amount).ok_or(Error::Overflow)?;
+        self.total = self.total.checked_add(amount).ok_or(Error::Overflow)?;
+        Ok(())
+    }
+
+    pub(crate) fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub(crate) fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub(crate) fn uncleared(&self) -> Decimal {
+        self.uncleared
+    }
+
+    pub(crate) fn total(&self) -> Decimal {
+        self.total
+    }
+}
+
+/// Account is responsible for updating values on account.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub(crate) struct Account {
+    client: u16,
+    balances: BTreeMap<String, Balance>,
+    // The account is locked while any of its locks isn't lifted.
+    locks: Vec<Lock>,
+    credit_limit: Decimal,
+    // Postings not yet recorded in the ledger.
+    #[serde(skip)]
+    unposted: Vec<Posting>,
 }
 
 impl Account {
-    fn new(id: u16) -> Self {
-        let mut account = Self::default();
-        account.client = id;
-        account
+    fn new(id: u16, credit_limit: Decimal) -> Self {
+        Self {
+            client: id,
+            credit_limit,
+            ..Self::default()
+        }
     }
 
-    fn deposit(&mut self, amount: Decimal) -> Result<()> {
-        self.available = self.available.checked_add(amount).ok_or(Error::Overflow)?;
-        self.total = self.total.checked_add(amount).ok_or(Error::Overflow)?;
-        Ok(())
+    fn balance_mut(&mut self, currency: &str) -> &mut Balance {
+        self.balances.entry(currency.to_string()).or_default()
     }
 
-    fn hold(&mut self, amount: Decimal) -> Result<()> {
-        self.held = self.held.checked_add(amount).ok_or(Error::Overflow)?;
-        self.total = self.total.checked_add(amount).ok_or(Error::Overflow)?;
+    // Posts to one of the account's books or to a system book.
+    fn post(&mut self, currency: &str, book: Book, amount: Decimal) -> Result<()> {
+        match book.client() {
+            Some(client) if client != self.client => return Err(Error::InvalidData),
+            Some(_) => self.balance_mut(currency).post(book, amount)?,
+            None => {}
+        }
+        self.unposted.push(Posting {
+            book,
+            currency: currency.to_string(),
+            amount,
+        });
         Ok(())
     }
 
-    fn withdraw(&mut self, amount: Decimal) -> Result<()> {
-        let available = self.available.checked_sub(amount).ok_or(Error::Overflow)?;
-        if available < Decimal::ZERO {
+    fn move_funds(&mut self, currency: &str, from: Book, to: Book, amount: Decimal) -> Result<()> {
+        self.post(currency, from, -amount)?;
+        self.post(currency, to, amount)
+    }
+
+    // Available funds may go below zero down to the credit limit.
+    fn check_available(&mut self, currency: &str, amount: Decimal) -> Result<()> {
+        let available = self
+            .balance_mut(currency)
+            .available
+            .checked_sub(amount)
+            .ok_or(Error::Overflow)?;
+        if available < -self.credit_limit {
             Err(Error::InsufficientFunds)
         } else {
-            self.available = available;
-            self.total = self.total.checked_sub(amount).ok_or(Error::Overflow)?;
             Ok(())
         }
     }
 
-    fn withdraw_held(&mut self, amount: Decimal) -> Result<()> {
-        let held = self.held.checked_sub(amount).ok_or(Error::Overflow)?;
-        if held < Decimal::ZERO {
+    fn check_held(&mut self, currency: &str, amount: Decimal) -> Result<()> {
+        if self.balance_mut(currency).held < amount {
             Err(Error::InsufficientFunds)
         } else {
-            self.held = held;
-            self.total = self.total.checked_sub(amount).ok_or(Error::Overflow)?;
             Ok(())
         }
     }
 
-    fn dispute(&mut self, amount: Decimal) -> Result<()> {
-        self.withdraw(amount)?;
-        self.hold(amount)?;
-        Ok(())
+    fn receive(&mut self, currency: &str, amount: Decimal, from: Book) -> Result<()> {
+        self.move_funds(currency, from, Book::Available(self.client), amount)
     }
 
-    fn resolve(&mut self, amount: Decimal) -> Result<()> {
-        self.withdraw_held(amount)?;
-        self.deposit(amount)?;
-        Ok(())
+    fn spend(&mut self, currency: &str, amount: Decimal, to: Book) -> Result<()> {
+        self.check_available(currency, amount)?;
+        self.move_funds(currency, Book::Available(self.client), to, amount)
     }
 
-    fn chargeback(&mut self, amount: Decimal) -> Result<()> {
-        self.withdraw_held(amount)?;
-        self.locked = true;
+    fn deposit(&mut self, currency: &str, amount: Decimal) -> Result<()> {
+        self.receive(currency, amount, Book::Funding)
+    }
+
+    fn deposit_uncleared(&mut self, currency: &str, amount: Decimal) -> Result<()> {
+        self.move_funds(
+            currency,
+            Book::Funding,
+            Book::Uncleared(self.client),
+            amount,
+        )
+    }
+
+    // Makes uncleared funds available.
+    fn clear(&mut self, currency: &str, amount: Decimal) -> Result<()> {
+        let client = self.client;
+        self.move_funds(
+            currency,
+            Book::Uncleared(client),
+            Book::Available(client),
+            amount,
+        )
+    }
+
+    fn withdraw(&mut self, currency: &str, amount: Decimal) -> Result<()> {
+        self.spend(currency, amount, Book::Payouts)
+    }
+
+    // Disputed funds are held even if they were already spent.
+    // Funds of deposits that haven't cleared are held from the uncleared funds.
+    fn dispute(&mut self, currency: &str, amount: Decimal, cleared: bool) -> Result<()> {
+        let from = if cleared {
+            Book::Available(self.client)
+        } else {
+            Book::Uncleared(self.client)
+        };
+        self.move_funds(currency, from, Book::Held(self.client), amount)
+    }
+
+    fn resolve(&mut self, currency: &str, amount: Decimal, cleared: bool) -> Result<()> {
+        self.check_held(currency, amount)?;
+        let to = if cleared {
+            Book::Available(self.client)
+        } else {
+            Book::Uncleared(self.client)
+        };
+        self.move_funds(currency, Book::Held(self.client), to, amount)
+    }
+
+    fn chargeback(&mut self, currency: &str, amount: Decimal, lock: Lock) -> Result<()> {
+        self.check_held(currency, amount)?;
+        self.move_funds(
+            currency,
+            Book::Held(self.client),
+            Book::ChargebackLoss,
+            amount,
+        )?;
+        self.locks.push(lock);
         Ok(())
     }
 
+    pub(crate) fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// Balances per currency. Accounts without any activity
+    /// report an empty balance in the default currency.
+    pub(crate) fn balances(&self) -> Vec<(&str, Balance)> {
+        if self.balances.is_empty() {
+            return vec![(DEFAULT_CURRENCY, Balance::default())];
+        }
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (currency.as_str(), balance.clone()))
+            .collect()
+    }
+
     pub(crate) fn frozen(&self) -> bool {
-        self.locked
+        self.locks.iter().any(|lock| lock.lifted.is_none())
+    }
+
+    /// Every lock the account had, in the order they were put on.
+    pub(crate) fn locks(&self) -> &[Lock] {
+        &self.locks
     }
 
     #[cfg(test)]
     pub(crate) fn available(&self) -> Decimal {
-        self.available
+        self.available_in(DEFAULT_CURRENCY)
     }
 
     #[cfg(test)]
     pub(crate) fn total(&self) -> Decimal {
-        self.total
+        self.total_in(DEFAULT_CURRENCY)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn available_in(&self, currency: &str) -> Decimal {
+        self.balances
+            .get(currency)
+            .map(Balance::available)
+            .unwrap_or_default()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn total_in(&self, currency: &str) -> Decimal {
+        self.balances
+            .get(currency)
+            .map(Balance::total)
+            .unwrap_or_default()
     }
 }
 
@@ -92,28 +251,445 @@ impl Account {
 /// updates to individual accounts and transactions.
 #[derive(Debug)]
 pub(crate) struct Accounts {
-    inner: HashMap<u16, Account>,
-    transactions: HashMap<u32, Transaction>,
+    store: Box<dyn Store>,
+    fees: Schedule,
+    // Client credited with the fees.
+    house: u16,
+    limits: CreditLimits,
+    // Moment of the record being processed.
+    now: Moment,
+    // Disputes can only be raised within this window of the transaction.
+    dispute_window: Option<Window>,
+    expiry: Option<Expiry>,
+    // Expired disputes whose settlement failed, by when they were raised.
+    // They're left to be resolved or charged back by hand.
+    failed_expiries: HashMap<u32, Moment>,
+    // Deposits only become available after this window.
+    clearing: Option<Window>,
+    unlock_on_representment: bool,
+    // Postings of the transaction being applied.
+    open: Vec<Posting>,
+    // Accounts put since the last journal entry, stored once it's recorded.
+    staged: BTreeMap<u16, Account>,
 }
 
 impl Accounts {
     pub(crate) fn new() -> Self {
+        Self::empty(Box::new(MemoryStore::default()))
+    }
+
+    /// Keeps the accounts and transactions in the given store,
+    /// going on from where a previous run left it.
+    pub(crate) fn with_store(store: Box<dyn Store>) -> Result<Self> {
+        let mut accounts = Self::empty(store);
+        accounts.now.position = accounts.store.position()?;
+        Ok(accounts)
+    }
+
+    fn empty(store: Box<dyn Store>) -> Self {
         Self {
-            inner: HashMap::new(),
-            transactions: HashMap::new(),
+            store,
+            fees: Schedule::default(),
+            house: DEFAULT_HOUSE_ACCOUNT,
+            limits: CreditLimits::default(),
+            now: Moment::default(),
+            dispute_window: None,
+            expiry: None,
+            failed_expiries: HashMap::new(),
+            clearing: None,
+            unlock_on_representment: false,
+            open: Vec::new(),
+            staged: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn set_dispute_window(&mut self, window: Option<Window>, expiry: Option<Expiry>) {
+        self.dispute_window = window;
+        self.expiry = expiry;
+    }
+
+    pub(crate) fn set_clearing(&mut self, clearing: Option<Window>) {
+        self.clearing = clearing;
+    }
+
+    pub(crate) fn set_unlock_on_representment(&mut self, unlock: bool) {
+        self.unlock_on_representment = unlock;
+    }
+
+    /// Visits the audit trail, in the order its entries were made.
+    pub(crate) fn audit(&self, f: Visit<AuditEntry>) -> Result<()> {
+        self.store.scan_audit(f)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn balance(&self, book: Book, currency: &str) -> Decimal {
+        self.store.balance(book, currency).unwrap()
+    }
+
+    /// Visits the journal of every transaction, in the order they were recorded.
+    pub(crate) fn entries(&self, f: Visit<Entry>) -> Result<()> {
+        self.store.scan_entries(f)
+    }
+
+    /// Position of the last record processed, including by previous runs of the store.
+    pub(crate) fn position(&self) -> u64 {
+        self.now.position
+    }
+
+    /// Starts a savepoint for changes that are kept or undone together.
+    pub(crate) fn begin(&mut self) -> Result<()> {
+        self.store.begin()
+    }
+
+    /// Undoes the changes since the last savepoint, of a record that was rejected.
+    pub(crate) fn rollback(&mut self) -> Result<()> {
+        self.drop_staged();
+        self.store.rollback()
+    }
+
+    /// Keeps the changes since the last savepoint, with the position of the record.
+    pub(crate) fn commit(&mut self) -> Result<()> {
+        self.store.put_position(self.now.position)?;
+        self.store.commit()
+    }
+
+    /// Visits the stored transactions in tx order, only those in the given statuses
+    /// if any are given.
+    pub(crate) fn transactions(&self, statuses: &[Status], f: Visit<TransactionRow>) -> Result<()> {
+        self.store.scan_transactions(&mut |trans| {
+            if statuses.is_empty() || statuses.contains(&trans.status) {
+                f(trans.row())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Visits the disputes waiting for a resolve or chargeback, oldest first.
+    pub(crate) fn pending_disputes(&self, f: Visit<DisputeRow>) -> Result<()> {
+        self.store.scan_pending(&mut |trans| {
+            if let Some((since, client)) = trans.dispute {
+                f(DisputeRow {
+                    tx: trans.id,
+                    client,
+                    kind: trans.kind.name(),
+                    currency: trans.currency,
+                    amount: trans.amount,
+                    position: since.position,
+                    timestamp: since.timestamp,
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    pub(crate) fn history(&self, client: u16) -> Result<Vec<Event>> {
+        self.store.history(client)
+    }
+
+    /// Moves on to the moment of the next record, settling the disputes that expired.
+    pub(crate) fn advance(&mut self, at: Moment) -> Result<()> {
+        self.now = at;
+        self.clear_deposits()?;
+        let expiry = match self.expiry {
+            Some(expiry) => expiry,
+            None => return Ok(()),
+        };
+        let mut expired = Vec::new();
+        self.store.scan_pending(&mut |trans| {
+            let since = trans.dispute.map(|(since, _)| since);
+            if since.is_some_and(|since| {
+                expiry.window.contains(since, at) == Some(false)
+                    && self.failed_expiries.get(&trans.id) != Some(&since)
+            }) {
+                expired.push(trans);
+            }
+            Ok(())
+        })?;
+        // Each settlement is kept or undone on its own.
+        for trans in expired {
+            let (since, client) = match trans.dispute {
+                Some(dispute) => dispute,
+                None => continue,
+            };
+            self.store.begin()?;
+            let (action, result) = match expiry.policy {
+                ExpiryPolicy::Resolve => ("auto-resolve", self.resolve(client, None, trans.id)),
+                ExpiryPolicy::Chargeback => {
+                    ("auto-chargeback", self.chargeback(client, None, trans.id))
+                }
+            };
+            let mut entry = AuditEntry::new(at, client, trans.id, action);
+            entry.currency = trans.currency.clone();
+            entry.amount = trans.amount;
+            entry.reason = format!("dispute expired after {}", expiry.window);
+            // A settlement that fails doesn't fail the record that triggered it.
+            if let Err(e) = result {
+                self.rollback()?;
+                log::error!("{} of tx {} failed: {}", action, trans.id, e);
+                self.failed_expiries.insert(trans.id, since);
+                entry.action = format!("{}-failed", action);
+                entry.reason = format!("{}: {}", entry.reason, e);
+                self.store.put_audit(entry)?;
+                continue;
+            }
+            self.store.commit()?;
+            // Disputes on locked accounts stay pending.
+            if let Some(Status::Pending) = self.transaction(trans.id)?.map(|trans| trans.status) {
+                continue;
+            }
+            self.store.put_audit(entry)?;
         }
+        Ok(())
+    }
+
+    pub(crate) fn set_credit_limits(&mut self, limits: CreditLimits) {
+        self.limits = limits;
     }
 
-    pub(crate) fn accounts(&self) -> Vec<&Account> {
-        self.inner.values().collect()
+    pub(crate) fn set_fees(&mut self, fees: Schedule, house: u16) {
+        self.fees = fees;
+        self.house = house;
+    }
+
+    /// Visits every fee posting, in tx order.
+    pub(crate) fn fee_postings(&self, f: Visit<FeePosting>) -> Result<()> {
+        self.store.scan_postings(f)
+    }
+
+    pub(crate) fn accounts(&self) -> Result<Vec<Account>> {
+        self.store.accounts()
     }
 
     pub(crate) fn account(&mut self, id: u16) -> Result<Account> {
-        Ok(self.inner.entry(id).or_insert(Account::new(id)).clone())
+        if let Some(account) = self.staged.get(&id) {
+            return Ok(account.clone());
+        }
+        if let Some(account) = self.store.account(id)? {
+            return Ok(account);
+        }
+        let account = Account::new(id, self.limits.limit(id));
+        self.store.put_account(account.clone())?;
+        Ok(account)
+    }
+
+    /// Starts an account off with a balance carried over from another system.
+    /// Each client and currency can only be opened once, before any record.
+    pub(crate) fn open(&mut self, opening: &OpeningBalance) -> Result<()> {
+        let client = opening.client;
+        let currency = opening.currency();
+        let mut account = self.account(client)?;
+        if account.balances.contains_key(&currency) {
+            return Err(Error::InvalidOpening(
+                client,
+                format!("{} balance opened twice", currency),
+            ));
+        }
+        account.balance_mut(&currency);
+        for (book, amount) in [
+            (Book::Available(client), opening.available),
+            (Book::Held(client), opening.held),
+            (Book::Uncleared(client), opening.uncleared()),
+        ] {
+            account.move_funds(&currency, Book::Opening, book, amount)?;
+        }
+        // Accounts locked by carried over locks don't need another.
+        if opening.locked && !account.frozen() {
+            account.locks.push(self.new_lock(None, "opening balance"));
+        }
+        self.put_account(account)?;
+        self.journal(None, |_| "opening")?;
+        self.add_opening(client, &currency, opening.held, opening.total)
+    }
+
+    /// Archives the transactions that can no longer change, visiting them in tx order.
+    /// Their funds stay accounted for with the opening balances.
+    /// Nothing is archived if any of them can't be.
+    pub(crate) fn close(&mut self, f: Visit<TransactionRow>) -> Result<()> {
+        self.store.begin()?;
+        match self.archive(f) {
+            Ok(()) => self.store.commit(),
+            Err(e) => {
+                self.store.rollback()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Visits the transactions that can still change, in tx order, to carry over to the next run.
+    pub(crate) fn carried(&self, f: Visit<CarriedTransaction>) -> Result<()> {
+        self.store.scan_transactions(&mut |trans| {
+            if !self.unsettled(&trans) {
+                return Ok(());
+            }
+            f(CarriedTransaction {
+                tx: trans.id,
+                client: trans.client,
+                kind: trans.kind.name().to_string(),
+                currency: trans.currency,
+                amount: trans.amount,
+                refunded: trans.refunded,
+                status: trans.status,
+                destination: trans.destination,
+                cleared: trans.cleared,
+                timestamp: trans.created.timestamp,
+                disputed_by: trans.dispute.map(|(_, client)| client),
+                dispute_timestamp: trans.dispute.and_then(|(since, _)| since.timestamp),
+            })
+        })
     }
 
-    pub(crate) fn deposit(&mut self, client: u16, amount: Decimal, tx: u32) -> Result<()> {
-        if self.transaction(tx).is_some() {
+    /// Visits the fees of the transactions carried over that can still be returned.
+    pub(crate) fn carried_fees(&self, f: Visit<FeePosting>) -> Result<()> {
+        self.store.scan_postings(&mut |posting| {
+            if !posting.reversed && self.transaction(posting.tx)?.is_some() {
+                f(posting)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Restores a fee carried over from the previous run with its transaction, so it can
+    /// still be returned. It's already part of the opening balances, so nothing is posted.
+    pub(crate) fn carry_fee(&mut self, posting: &FeePosting) -> Result<()> {
+        if self.transaction(posting.tx)?.is_none() {
+            return Err(Error::InvalidData);
+        }
+        let mut postings = self.store.postings(posting.tx)?;
+        postings.push(posting.clone());
+        self.store.put_postings(posting.tx, postings)
+    }
+
+    /// Visits the ids of every transaction archived so far, including by previous runs.
+    pub(crate) fn archived(&self, f: Visit<ArchivedTransaction>) -> Result<()> {
+        self.store
+            .scan_archived(&mut |tx| f(ArchivedTransaction { tx }))
+    }
+
+    /// Keeps the id of a transaction archived by a previous run from being reused.
+    pub(crate) fn carry_archived(&mut self, archived: &ArchivedTransaction) -> Result<()> {
+        self.store.put_archived(archived.tx)
+    }
+
+    /// Every lock of every account, lifted or not, to carry over to the next run.
+    pub(crate) fn carried_locks(&self) -> Result<Vec<CarriedLock>> {
+        let mut accounts = self.store.accounts()?;
+        accounts.sort_by_key(|account| account.client);
+        let rows = accounts
+            .iter()
+            .flat_map(|account| {
+                account.locks.iter().map(|lock| CarriedLock {
+                    client: account.client,
+                    tx: lock.tx,
+                    timestamp: lock.at.timestamp,
+                    reason: lock.reason.clone(),
+                    currency: lock.currency.clone(),
+                    amount: lock.amount,
+                    lifted: lock.lifted.is_some(),
+                    lifted_timestamp: lock.lifted.and_then(|at| at.timestamp),
+                })
+            })
+            .collect();
+        Ok(rows)
+    }
+
+    /// Restores a lock carried over from the previous run, before the opening balances.
+    pub(crate) fn carry_lock(&mut self, carried: &CarriedLock) -> Result<()> {
+        let mut account = self.account(carried.client)?;
+        let at = |timestamp| Moment {
+            position: 0,
+            timestamp,
+        };
+        account.locks.push(Lock {
+            tx: carried.tx,
+            at: at(carried.timestamp),
+            reason: carried.reason.clone(),
+            currency: carried.currency.clone(),
+            amount: carried.amount,
+            lifted: carried.lifted.then(|| at(carried.lifted_timestamp)),
+        });
+        // Locks don't post anything, so the account is stored right away.
+        self.store.put_account(account)
+    }
+
+    /// Restores a transaction carried over from the previous run.
+    /// Its funds are already part of the opening balances, so nothing is posted.
+    pub(crate) fn carry(&mut self, carried: &CarriedTransaction) -> Result<()> {
+        if self.exists(carried.tx)? {
+            return Err(Error::TxExists);
+        }
+        let kind = Kind::parse(&carried.kind).ok_or(Error::InvalidData)?;
+        let mut trans = Transaction::new(
+            carried.tx,
+            carried.client,
+            kind,
+            &carried.currency,
+            carried.amount,
+        );
+        // Windows of the previous run start over at the first record.
+        trans.created = Moment {
+            position: 0,
+            timestamp: carried.timestamp,
+        };
+        trans.status = carried.status.clone();
+        trans.destination = carried.destination;
+        trans.cleared = carried.cleared;
+        trans.refunded = carried.refunded;
+        trans.history.push((trans.created, trans.status.clone()));
+        trans.dispute = carried.disputed_by.map(|client| {
+            let since = Moment {
+                position: 0,
+                timestamp: carried.dispute_timestamp,
+            };
+            (since, client)
+        });
+        if let (Status::Pending | Status::Chargeback, None) = (&trans.status, trans.dispute) {
+            return Err(Error::InvalidData);
+        }
+        self.set_aside(&trans, Decimal::NEGATIVE_ONE)?;
+        self.store.put_transaction(trans)
+    }
+
+    /// Locks an account so its records are ignored.
+    pub(crate) fn lock(&mut self, client: u16, reason: &str) -> Result<()> {
+        let mut account = self.account(client)?;
+        account.locks.push(self.new_lock(None, reason));
+        self.store.put_account(account)
+    }
+
+    /// Active locks of every locked account, by client.
+    pub(crate) fn locked_accounts(&self) -> Result<Vec<LockRow>> {
+        let mut rows: Vec<LockRow> = self
+            .store
+            .accounts()?
+            .iter()
+            .flat_map(|account| {
+                account
+                    .locks()
+                    .iter()
+                    .filter(|lock| lock.lifted.is_none())
+                    .map(|lock| LockRow {
+                        client: account.client,
+                        tx: lock.tx,
+                        position: lock.at.position,
+                        timestamp: lock.at.timestamp,
+                        reason: lock.reason.clone(),
+                        currency: lock.currency.clone(),
+                        amount: lock.amount,
+                    })
+            })
+            .collect();
+        rows.sort_by_key(|row| (row.client, row.position));
+        Ok(rows)
+    }
+
+    pub(crate) fn deposit(
+        &mut self,
+        client: u16,
+        currency: &str,
+        amount: Decimal,
+        tx: u32,
+    ) -> Result<()> {
+        if self.exists(tx)? {
             return Err(Error::TxExists);
         }
 
@@ -121,15 +697,27 @@ impl Accounts {
         if account.frozen() {
             return Ok(());
         }
-        account.deposit(amount)?;
+        let mut trans = Transaction::new(tx, client, Kind::Deposit, currency, amount);
+        if self.clearing.is_some() {
+            account.deposit_uncleared(currency, amount)?;
+            trans.cleared = false;
+        } else {
+            account.deposit(currency, amount)?;
+        }
         self.put_account(account)?;
         // Record transaction.
-        self.put_transaction(Transaction::new(tx, amount))?;
+        self.put_transaction(trans)?;
         Ok(())
     }
 
-    pub(crate) fn withdraw(&mut self, client: u16, amount: Decimal, tx: u32) -> Result<()> {
-        if self.transaction(tx).is_some() {
+    pub(crate) fn withdraw(
+        &mut self,
+        client: u16,
+        currency: &str,
+        amount: Decimal,
+        tx: u32,
+    ) -> Result<()> {
+        if self.exists(tx)? {
             return Err(Error::TxExists);
         }
 
@@ -137,112 +725,1205 @@ impl Accounts {
         if account.frozen() {
             return Ok(());
         }
-        account.withdraw(amount)?;
+        account.withdraw(currency, amount)?;
+        self.charge_fee(&mut account, FeeType::Withdrawal, tx, currency, amount)?;
         self.put_account(account)?;
         // Record transaction.
-        self.put_transaction(Transaction::new(tx, amount))?;
+        self.put_transaction(Transaction::new(
+            tx,
+            client,
+            Kind::Withdrawal,
+            currency,
+            amount,
+        ))?;
+        Ok(())
+    }
+
+    /// Moves funds from one client to another.
+    /// Both accounts are updated or neither is.
+    pub(crate) fn transfer(
+        &mut self,
+        client: u16,
+        destination: u16,
+        currency: &str,
+        amount: Decimal,
+        tx: u32,
+    ) -> Result<()> {
+        if client == destination || amount <= Decimal::ZERO {
+            return Err(Error::InvalidData);
+        }
+        if self.exists(tx)? {
+            return Err(Error::TxExists);
+        }
+
+        let mut source = self.account(client)?;
+        if source.frozen() || self.account(destination)?.frozen() {
+            return Ok(());
+        }
+        source.spend(currency, amount, Book::Transfers)?;
+        self.charge_fee(&mut source, FeeType::Transfer, tx, currency, amount)?;
+        // The destination is read after the fee, which it receives if it's the house account.
+        let mut target = self.account(destination)?;
+        target.receive(currency, amount, Book::Transfers)?;
+        self.put_account(source)?;
+        self.put_account(target)?;
+        // Record transaction.
+        let mut trans = Transaction::new(tx, client, Kind::Transfer, currency, amount);
+        trans.destination = Some(destination);
+        self.put_transaction(trans)?;
+        Ok(())
+    }
+
+    /// Reserves funds for a later capture, moving them from available to held.
+    pub(crate) fn authorize(
+        &mut self,
+        client: u16,
+        currency: &str,
+        amount: Decimal,
+        tx: u32,
+    ) -> Result<()> {
+        if amount <= Decimal::ZERO {
+            return Err(Error::InvalidData);
+        }
+        if self.exists(tx)? {
+            return Err(Error::TxExists);
+        }
+
+        let mut account = self.account(client)?;
+        if account.frozen() {
+            return Ok(());
+        }
+        account.spend(currency, amount, Book::Held(client))?;
+        self.put_account(account)?;
+        // Record transaction.
+        let mut trans = Transaction::new(tx, client, Kind::Authorization, currency, amount);
+        trans.status = Status::Authorized;
+        self.put_transaction(trans)?;
+        Ok(())
+    }
+
+    /// Finalizes the debit of an authorization.
+    pub(crate) fn capture(&mut self, client: u16, tx: u32) -> Result<()> {
+        if let Some(mut trans) = self.authorization(client, tx)? {
+            let mut account = self.account(client)?;
+            if account.frozen() {
+                return Ok(());
+            }
+
+            if let Status::Authorized = trans.status {
+                account.check_held(&trans.currency, trans.amount)?;
+                account.move_funds(
+                    &trans.currency,
+                    Book::Held(client),
+                    Book::Payouts,
+                    trans.amount,
+                )?;
+                trans.status = Status::Captured;
+                self.put_account(account)?;
+                self.put_transaction(trans)?;
+            }
+        }
         Ok(())
     }
 
-    pub(crate) fn dispute(&mut self, client: u16, tx: u32) -> Result<()> {
-        if let Some(mut trans) = self.transaction(tx) {
+    /// Returns the funds of an authorization to the client.
+    pub(crate) fn release(&mut self, client: u16, tx: u32) -> Result<()> {
+        if let Some(mut trans) = self.authorization(client, tx)? {
+            let mut account = self.account(client)?;
+            if account.frozen() {
+                return Ok(());
+            }
+
+            if let Status::Authorized = trans.status {
+                account.resolve(&trans.currency, trans.amount, true)?;
+                trans.status = Status::Released;
+                self.put_account(account)?;
+                self.put_transaction(trans)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dispute(&mut self, client: u16, currency: Option<&str>, tx: u32) -> Result<()> {
+        if let Some(mut trans) = self.disputable(client, currency, tx)? {
             let mut account = self.account(client)?;
             if account.frozen() {
                 return Ok(());
             }
 
             if let Status::Open = trans.status {
-                if let Err(Error::InsufficientFunds) = account.dispute(trans.amount) {
-                    return Ok(());
+                if let Some(window) = self.dispute_window {
+                    if window.contains(trans.created, self.now) == Some(false) {
+                        return Err(Error::DisputeWindowClosed(tx));
+                    }
                 }
+                account.dispute(&trans.currency, trans.amount, trans.cleared)?;
+                self.charge_fee_if_funded(&mut account, FeeType::Dispute, &trans)?;
                 trans.status = Status::Pending;
+                trans.dispute = Some((self.now, client));
+                self.put_account(account)?;
                 let new_trans = trans.clone();
                 self.put_transaction(new_trans)?;
             }
-
-            self.put_account(account)?;
         }
         Ok(())
     }
 
-    pub(crate) fn resolve(&mut self, client: u16, tx: u32) -> Result<()> {
-        if let Some(mut trans) = self.transaction(tx) {
+    pub(crate) fn resolve(&mut self, client: u16, currency: Option<&str>, tx: u32) -> Result<()> {
+        if let Some(mut trans) = self.disputable(client, currency, tx)? {
             let mut account = self.account(client)?;
             if account.frozen() {
                 return Ok(());
             }
 
             if let Status::Pending = trans.status {
-                if let Err(Error::InsufficientFunds) = account.resolve(trans.amount) {
+                if let Err(Error::InsufficientFunds) =
+                    account.resolve(&trans.currency, trans.amount, trans.cleared)
+                {
                     return Ok(());
                 }
+                self.reverse_fees(&mut account, FeeType::Dispute, tx)?;
                 trans.status = Status::Resolved;
+                self.put_account(account)?;
                 let new_trans = trans.clone();
                 self.put_transaction(new_trans)?;
             }
-
-            self.put_account(account)?;
         }
         Ok(())
     }
 
-    pub(crate) fn chargeback(&mut self, client: u16, tx: u32) -> Result<()> {
-        if let Some(mut trans) = self.transaction(tx) {
+    pub(crate) fn chargeback(
+        &mut self,
+        client: u16,
+        currency: Option<&str>,
+        tx: u32,
+    ) -> Result<()> {
+        if let Some(mut trans) = self.disputable(client, currency, tx)? {
             let mut account = self.account(client)?;
             if account.frozen() {
                 return Ok(());
             }
 
             if let Status::Pending = trans.status {
-                if let Err(Error::InsufficientFunds) = account.chargeback(trans.amount) {
+                let mut lock = self.new_lock(Some(tx), "chargeback");
+                lock.currency = trans.currency.clone();
+                lock.amount = trans.amount;
+                if let Err(Error::InsufficientFunds) =
+                    account.chargeback(&trans.currency, trans.amount, lock)
+                {
                     return Ok(());
                 }
+                self.charge_fee_if_funded(&mut account, FeeType::Chargeback, &trans)?;
+                // Charged back transfers return the funds to the sender.
+                if let Kind::Transfer = trans.kind {
+                    let mut source = self.account(trans.client)?;
+                    source.receive(&trans.currency, trans.amount, Book::ChargebackLoss)?;
+                    self.put_account(source)?;
+                }
                 trans.status = Status::Chargeback;
+                self.put_account(account)?;
                 let new_trans = trans.clone();
                 self.put_transaction(new_trans)?;
             }
+        }
+        Ok(())
+    }
 
-            self.put_account(account)?;
+    /// Corrects a balance by hand. Positive amounts credit the bucket and negative amounts debit it.
+    /// Adjustments apply to locked accounts too, and are always written to the audit trail.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn adjust(
+        &mut self,
+        client: u16,
+        currency: &str,
+        bucket: Bucket,
+        amount: Decimal,
+        tx: u32,
+        reason: &str,
+        operator: &str,
+    ) -> Result<()> {
+        let invalid = |reason: &str| Error::InvalidAdjustment(tx, reason.to_string());
+        if reason.is_empty() || operator.is_empty() {
+            return Err(invalid("reason and operator are required"));
+        }
+        if amount.is_zero() {
+            return Err(invalid("amount is zero"));
+        }
+        if self.exists(tx)? {
+            return Err(Error::TxExists);
         }
+
+        let mut account = self.account(client)?;
+        match (bucket, amount > Decimal::ZERO) {
+            (Bucket::Available, true) => account.receive(currency, amount, Book::Adjustments)?,
+            (Bucket::Available, false) => account.spend(currency, -amount, Book::Adjustments)?,
+            (Bucket::Held, true) => {
+                account.move_funds(currency, Book::Adjustments, Book::Held(client), amount)?
+            }
+            (Bucket::Held, false) => {
+                account.check_held(currency, -amount)?;
+                account.move_funds(currency, Book::Held(client), Book::Adjustments, -amount)?
+            }
+        }
+        self.put_account(account)?;
+        let mut entry = AuditEntry::new(self.now, client, tx, "adjustment");
+        entry.currency = currency.to_string();
+        entry.amount = amount;
+        entry.reason = reason.to_string();
+        entry.operator = operator.to_string();
+        self.store.put_audit(entry)?;
+        self.put_transaction(Transaction::new(
+            tx,
+            client,
+            Kind::Adjustment(bucket),
+            currency,
+            amount,
+        ))?;
         Ok(())
     }
 
-    fn transaction(&self, id: u32) -> Option<Transaction> {
-        self.transactions.get(&id).cloned()
+    /// Reverses a chargeback the merchant won, crediting the funds back to the client.
+    /// The account is unlocked if configured to.
+    pub(crate) fn represent(&mut self, client: u16, currency: Option<&str>, tx: u32) -> Result<()> {
+        let mut trans = match self.disputable(client, currency, tx)? {
+            Some(trans) if trans.dispute.is_some_and(|(_, by)| by == client) => trans,
+            _ => return Ok(()),
+        };
+        if trans.status != Status::Chargeback {
+            return Ok(());
+        }
+
+        // Locked accounts can be represented, since the chargeback locked them.
+        let mut account = self.account(client)?;
+        if let Kind::Transfer = trans.kind {
+            // Funds returned to the sender go back to the receiver.
+            let mut source = self.account(trans.client)?;
+            source.move_funds(
+                &trans.currency,
+                Book::Available(trans.client),
+                Book::ChargebackLoss,
+                trans.amount,
+            )?;
+            self.put_account(source)?;
+        }
+        account.receive(&trans.currency, trans.amount, Book::ChargebackLoss)?;
+        // Only the lock of this chargeback is lifted, the account stays locked by any other.
+        if self.unlock_on_representment {
+            for lock in account.locks.iter_mut() {
+                if lock.tx == Some(tx) && lock.lifted.is_none() {
+                    lock.lifted = Some(self.now);
+                }
+            }
+        }
+        self.put_account(account)?;
+        trans.status = Status::Represented;
+        let mut entry = AuditEntry::new(self.now, client, tx, "representment");
+        entry.currency = trans.currency.clone();
+        entry.amount = trans.amount;
+        self.store.put_audit(entry)?;
+        self.put_transaction(trans)?;
+        Ok(())
     }
 
-    fn put_account(&mut self, account: Account) -> Result<()> {
-        self.inner.insert(account.client, account);
+    /// Returns part or all of a deposit to the payer, or of a withdrawal to the client.
+    /// Without an amount, whatever wasn't refunded yet is refunded.
+    pub(crate) fn refund(
+        &mut self,
+        client: u16,
+        currency: Option<&str>,
+        amount: Option<Decimal>,
+        tx: u32,
+    ) -> Result<()> {
+        let invalid = |reason: &str| Error::InvalidRefund(tx, reason.to_string());
+        let mut trans = self
+            .transaction(tx)?
+            .filter(|trans| trans.client == client)
+            .ok_or_else(|| invalid("no such transaction of the client"))?;
+        if currency.is_some_and(|currency| currency != trans.currency) {
+            return Err(Error::CurrencyMismatch);
+        }
+        if !matches!(trans.kind, Kind::Deposit | Kind::Withdrawal) {
+            return Err(invalid("only deposits and withdrawals are refunded"));
+        }
+        if !matches!(trans.status, Status::Open | Status::Refunded) {
+            return Err(invalid("transaction is disputed or reversed"));
+        }
+        let refundable = trans.amount - trans.refunded;
+        let amount = amount.unwrap_or(refundable);
+        if amount <= Decimal::ZERO || amount > refundable {
+            return Err(invalid("amount exceeds what is left to refund"));
+        }
+
+        let mut account = self.account(client)?;
+        if account.frozen() {
+            return Ok(());
+        }
+        match trans.kind {
+            Kind::Deposit if !trans.cleared => account.move_funds(
+                &trans.currency,
+                Book::Uncleared(client),
+                Book::Funding,
+                amount,
+            )?,
+            Kind::Deposit => account.spend(&trans.currency, amount, Book::Funding)?,
+            _ => account.receive(&trans.currency, amount, Book::Payouts)?,
+        }
+        self.put_account(account)?;
+        trans.refunded += amount;
+        trans.status = Status::Refunded;
+        self.put_transaction(trans)?;
         Ok(())
     }
 
-    fn put_transaction(&mut self, tx: Transaction) -> Result<()> {
-        self.transactions.insert(tx.id, tx);
+    /// Takes back a deposit that was made in error.
+    pub(crate) fn reverse(&mut self, client: u16, currency: Option<&str>, tx: u32) -> Result<()> {
+        let invalid = |reason: &str| Error::InvalidReversal(tx, reason.to_string());
+        let mut trans = self
+            .transaction(tx)?
+            .filter(|trans| trans.client == client)
+            .ok_or_else(|| invalid("no such transaction of the client"))?;
+        if currency.is_some_and(|currency| currency != trans.currency) {
+            return Err(Error::CurrencyMismatch);
+        }
+        if !matches!(trans.kind, Kind::Deposit) {
+            return Err(invalid("only deposits are reversed"));
+        }
+        if trans.status != Status::Open {
+            return Err(invalid("transaction is disputed, refunded or reversed"));
+        }
+
+        let mut account = self.account(client)?;
+        if account.frozen() {
+            return Ok(());
+        }
+        if trans.cleared {
+            account.spend(&trans.currency, trans.amount, Book::Funding)?;
+        } else {
+            account.move_funds(
+                &trans.currency,
+                Book::Uncleared(client),
+                Book::Funding,
+                trans.amount,
+            )?;
+        }
+        self.put_account(account)?;
+        trans.status = Status::Reversed;
+        self.put_transaction(trans)?;
         Ok(())
     }
+
+    /// Checks that the balances add up, returning every invariant that doesn't hold and why:
+    /// totals are the sum of their parts, held funds are those of pending disputes, authorizations
+    /// and held adjustments and opening balances, and clients hold what came in minus what went out
+    /// in every currency.
+    pub(crate) fn violations(&self) -> Result<Vec<(String, String)>> {
+        let mut violations = Vec::new();
+        let accounts = self.store.accounts()?;
+        let mut held: HashMap<(u16, String), Decimal> = HashMap::new();
+        let mut flows: BTreeMap<String, Decimal> = BTreeMap::new();
+        self.store
+            .scan_opening(&mut |((client, currency), (opening_held, opening_total))| {
+                add(flows.entry(currency.clone()).or_default(), opening_total)?;
+                add(held.entry((client, currency)).or_default(), opening_held)
+            })?;
+        self.store.scan_transactions(&mut |trans| {
+            let (holder, flow) = trans.effects();
+            if let Some(client) = holder {
+                let key = (client, trans.currency.clone());
+                add(held.entry(key).or_default(), trans.amount)?;
+            }
+            add(flows.entry(trans.currency).or_default(), flow)
+        })?;
+
+        let mut totals: BTreeMap<&str, Decimal> = BTreeMap::new();
+        for account in &accounts {
+            for (currency, balance) in &account.balances {
+                let client = account.client;
+                let parts = balance
+                    .available
+                    .checked_add(balance.held)
+                    .and_then(|sum| sum.checked_add(balance.uncleared))
+                    .ok_or(Error::Overflow)?;
+                if balance.total != parts {
+                    violations.push((
+                        format!("client {} {} total", client, currency),
+                        format!(
+                            "total {} is not the sum of its parts {}",
+                            balance.total, parts
+                        ),
+                    ));
+                }
+                let expected = held.remove(&(client, currency.clone())).unwrap_or_default();
+                if balance.held != expected {
+                    violations.push((
+                        format!("client {} {} held", client, currency),
+                        format!(
+                            "held {} is not the {} of pending disputes and authorizations",
+                            balance.held, expected
+                        ),
+                    ));
+                }
+                add(totals.entry(currency).or_default(), balance.total)?;
+            }
+        }
+        for ((client, currency), expected) in held {
+            violations.push((
+                format!("client {} {} held", client, currency),
+                format!(
+                    "no funds held for {} of pending disputes and authorizations",
+                    expected
+                ),
+            ));
+        }
+
+        let currencies: BTreeSet<&str> = totals
+            .keys()
+            .copied()
+            .chain(flows.keys().map(String::as_str))
+            .collect();
+        for currency in currencies {
+            let total = totals.get(currency).copied().unwrap_or_default();
+            let flow = flows.get(currency).copied().unwrap_or_default();
+            if total != flow {
+                violations.push((
+                    format!("{} conservation", currency),
+                    format!(
+                        "clients hold {} but {} came in net of withdrawals and chargebacks",
+                        total, flow
+                    ),
+                ));
+            }
+            let outside = ledger::outside(&*self.store, currency)?;
+            if total != -outside {
+                violations.push((
+                    format!("{} ledger", currency),
+                    format!(
+                        "clients hold {} but the system books account for {}",
+                        total, -outside
+                    ),
+                ));
+            }
+        }
+        Ok(violations)
+    }
+
+    // Changes available funds without posting to the ledger.
+    #[cfg(test)]
+    pub(crate) fn skew(&mut self, client: u16, amount: Decimal) {
+        let mut account = self.store.account(client).unwrap().unwrap();
+        account.balance_mut(DEFAULT_CURRENCY).available += amount;
+        self.store.put_account(account).unwrap();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn status_history(&self, tx: u32) -> Vec<Status> {
+        self.transaction(tx)
+            .unwrap()
+            .map(|trans| {
+                trans
+                    .history
+                    .into_iter()
+                    .map(|(_, status)| status)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Makes deposits available once their clearing period is over.
+    // A period in seconds is over right away for deposits without timestamps.
+    fn clear_deposits(&mut self) -> Result<()> {
+        let clearing = match self.clearing {
+            Some(clearing) => clearing,
+            None => return Ok(()),
+        };
+        while let Some(mut trans) = self.store.oldest_uncleared()? {
+            if clearing.contains(trans.created, self.now) == Some(true) {
+                break;
+            }
+            // Funds of disputed, charged back or reversed deposits are no longer uncleared.
+            if let Status::Open | Status::Resolved | Status::Refunded = trans.status {
+                let mut account = self.account(trans.client)?;
+                account.clear(&trans.currency, trans.amount - trans.refunded)?;
+                self.put_account(account)?;
+            }
+            trans.cleared = true;
+            self.put_transaction(trans)?;
+        }
+        Ok(())
+    }
+
+    // Debits the fee for an operation from the account and credits it to the house account.
+    fn charge_fee(
+        &mut self,
+        account: &mut Account,
+        ty: FeeType,
+        tx: u32,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<()> {
+        let fee = self.fees.fee(ty, amount)?;
+        if fee.is_zero() {
+            return Ok(());
+        }
+        account.spend(currency, fee, Book::Fees)?;
+        // Fees of the house account are credited back to the account in hand.
+        if account.client == self.house {
+            account.receive(currency, fee, Book::Fees)?;
+        } else {
+            let mut house = self.account(self.house)?;
+            house.receive(currency, fee, Book::Fees)?;
+            self.put_account(house)?;
+        }
+        let mut postings = self.store.postings(tx)?;
+        postings.push(FeePosting {
+            tx,
+            client: account.client,
+            ty,
+            currency: currency.to_string(),
+            amount: fee,
+            reversed: false,
+        });
+        self.store.put_postings(tx, postings)
+    }
+
+    // Disputes and chargebacks go through even if the client can't pay the fee.
+    fn charge_fee_if_funded(
+        &mut self,
+        account: &mut Account,
+        ty: FeeType,
+        trans: &Transaction,
+    ) -> Result<()> {
+        match self.charge_fee(account, ty, trans.id, &trans.currency, trans.amount) {
+            Err(Error::InsufficientFunds) => {
+                log::warn!("insufficient funds for {:?} fee on tx {}", ty, trans.id);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    // Returns the fees of the given type charged for a transaction to the client.
+    // The house pays them back even if it has paid its fees out, so disputes can be resolved.
+    fn reverse_fees(&mut self, account: &mut Account, ty: FeeType, tx: u32) -> Result<()> {
+        let mut postings = self.store.postings(tx)?;
+        for posting in postings.iter_mut() {
+            if posting.ty != ty || posting.reversed {
+                continue;
+            }
+            let from = Book::Available(self.house);
+            if account.client == self.house {
+                account.move_funds(&posting.currency, from, Book::Fees, posting.amount)?;
+            } else {
+                let mut house = self.account(self.house)?;
+                house.move_funds(&posting.currency, from, Book::Fees, posting.amount)?;
+                self.put_account(house)?;
+            }
+            account.receive(&posting.currency, posting.amount, Book::Fees)?;
+            posting.reversed = true;
+        }
+        self.store.put_postings(tx, postings)
+    }
+
+    fn new_lock(&self, tx: Option<u32>, reason: &str) -> Lock {
+        Lock {
+            tx,
+            at: self.now,
+            reason: reason.to_string(),
+            currency: String::new(),
+            amount: Decimal::ZERO,
+            lifted: None,
+        }
+    }
+
+    // Whether a transaction can still change: disputes and authorizations waiting to be settled,
+    // chargebacks that can be represented, deposits that haven't cleared, deposits and
+    // withdrawals that can still be refunded and transactions that can still be disputed.
+    fn unsettled(&self, trans: &Transaction) -> bool {
+        match (&trans.kind, &trans.status) {
+            (_, Status::Pending | Status::Authorized | Status::Chargeback) => true,
+            _ if !trans.cleared => true,
+            (Kind::Deposit | Kind::Withdrawal, Status::Open | Status::Refunded)
+                if trans.refunded < trans.amount =>
+            {
+                true
+            }
+            (Kind::Adjustment(_), _) => false,
+            (_, Status::Open) => self
+                .dispute_window
+                .is_none_or(|window| window.contains(trans.created, self.now) != Some(false)),
+            _ => false,
+        }
+    }
+
+    // Adds the funds of a transaction to those of the opening balances, when it's archived,
+    // or takes them out, when it's carried over from balances that include them.
+    fn set_aside(&mut self, trans: &Transaction, sign: Decimal) -> Result<()> {
+        for (client, held, total) in trans.aside() {
+            self.add_opening(client, &trans.currency, sign * held, sign * total)?;
+        }
+        Ok(())
+    }
+
+    // Removes the transactions that can no longer change, setting their funds aside.
+    fn archive(&mut self, f: Visit<TransactionRow>) -> Result<()> {
+        let mut settled = Vec::new();
+        let mut funds: BTreeMap<(u16, String), (Decimal, Decimal)> = BTreeMap::new();
+        self.store.scan_transactions(&mut |trans| {
+            if self.unsettled(&trans) {
+                return Ok(());
+            }
+            for (client, held, total) in trans.aside() {
+                let aside = funds.entry((client, trans.currency.clone())).or_default();
+                add(&mut aside.0, held)?;
+                add(&mut aside.1, total)?;
+            }
+            settled.push(trans.id);
+            f(trans.row())
+        })?;
+        for tx in settled {
+            self.store.remove_transaction(tx)?;
+            self.store.put_archived(tx)?;
+        }
+        for ((client, currency), (held, total)) in funds {
+            self.add_opening(client, &currency, held, total)?;
+        }
+        Ok(())
+    }
+
+    // Adds to the opening funds of a client and currency.
+    fn add_opening(
+        &mut self,
+        client: u16,
+        currency: &str,
+        held: Decimal,
+        total: Decimal,
+    ) -> Result<()> {
+        let key = (client, currency.to_string());
+        let mut funds = self.store.opening(&key)?.unwrap_or_default();
+        add(&mut funds.0, held)?;
+        add(&mut funds.1, total)?;
+        self.store.put_opening(key, funds)
+    }
+
+    fn transaction(&self, id: u32) -> Result<Option<Transaction>> {
+        self.store.transaction(id)
+    }
+
+    // Whether a transaction id was used, by a stored or an archived transaction.
+    fn exists(&self, tx: u32) -> Result<bool> {
+        Ok(self.store.is_archived(tx)? || self.transaction(tx)?.is_some())
+    }
+
+    // Captures and releases only apply to authorizations of the same client.
+    fn authorization(&self, client: u16, tx: u32) -> Result<Option<Transaction>> {
+        Ok(self
+            .transaction(tx)?
+            .filter(|trans| matches!(trans.kind, Kind::Authorization) && trans.client == client))
+    }
+
+    // Disputes on a transfer are raised by the client that received the funds,
+    // and on other transactions by their own client. Adjustments can't be disputed.
+    // Disputes that name a currency must use the currency of the transaction.
+    fn disputable(
+        &self,
+        client: u16,
+        currency: Option<&str>,
+        tx: u32,
+    ) -> Result<Option<Transaction>> {
+        let trans = self.transaction(tx)?.filter(|trans| match trans.kind {
+            Kind::Transfer => trans.destination == Some(client),
+            Kind::Adjustment(_) => false,
+            _ => trans.client == client,
+        });
+        if let (Some(trans), Some(currency)) = (&trans, currency) {
+            if trans.currency != currency {
+                return Err(Error::CurrencyMismatch);
+            }
+        }
+        Ok(trans)
+    }
+
+    // The postings of the account are part of the journal entry of the next transaction put,
+    // and the account is only stored once they're recorded.
+    fn put_account(&mut self, mut account: Account) -> Result<()> {
+        self.open.append(&mut account.unposted);
+        self.staged.insert(account.client, account);
+        Ok(())
+    }
+
+    // Drops the accounts and postings of an operation that failed before it was journaled.
+    fn drop_staged(&mut self) {
+        self.open.clear();
+        self.staged.clear();
+    }
+
+    fn put_transaction(&mut self, mut tx: Transaction) -> Result<()> {
+        // New transactions are stamped with the moment they were recorded,
+        // and every change of status is kept in the history.
+        let previous = self.transaction(tx.id)?;
+        match &previous {
+            None => {
+                tx.created = self.now;
+                tx.history.push((self.now, tx.status.clone()));
+            }
+            Some(previous) if previous.status != tx.status => {
+                tx.history.push((self.now, tx.status.clone()));
+            }
+            _ => {}
+        }
+        // Clients other than those of the transaction are only touched by fees.
+        let action = action(previous.as_ref(), &tx);
+        self.journal(Some(tx.id), |client| {
+            let party = client == tx.client
+                || tx.destination == Some(client)
+                || tx.dispute.is_some_and(|(_, by)| by == client);
+            if party {
+                action
+            } else {
+                "fee"
+            }
+        })?;
+        self.store.put_transaction(tx)
+    }
+
+    // Records the postings of the accounts put since the last entry in the ledger, then stores
+    // the accounts and the events they make up in the histories of the clients they touched.
+    // Nothing is stored if the entry can't be recorded.
+    fn journal(&mut self, tx: Option<u32>, action: impl Fn(u16) -> &'static str) -> Result<()> {
+        let postings = std::mem::take(&mut self.open);
+        let staged = std::mem::take(&mut self.staged);
+        if postings.is_empty() {
+            for account in staged.into_values() {
+                self.store.put_account(account)?;
+            }
+            return Ok(());
+        }
+        let mut changes: BTreeMap<(u16, String), Decimal> = BTreeMap::new();
+        for posting in &postings {
+            if let Some(client) = posting.book.client() {
+                let change = changes
+                    .entry((client, posting.currency.clone()))
+                    .or_default();
+                add(change, posting.amount)?;
+            }
+        }
+        ledger::record(&mut *self.store, self.now, tx, postings)?;
+        for account in staged.into_values() {
+            self.store.put_account(account)?;
+        }
+        for ((client, currency), amount) in changes {
+            let balance = self
+                .store
+                .account(client)?
+                .and_then(|account| account.balances.get(&currency).cloned())
+                .unwrap_or_default();
+            let event = Event {
+                position: self.now.position,
+                timestamp: self.now.timestamp,
+                tx,
+                action: action(client).to_string(),
+                currency,
+                amount,
+                available: balance.available,
+                held: balance.held,
+                uncleared: balance.uncleared,
+                total: balance.total,
+            };
+            self.store.put_event(client, event)?;
+        }
+        Ok(())
+    }
+}
+
+// Adds an amount to a sum, failing on overflow.
+fn add(sum: &mut Decimal, amount: Decimal) -> Result<()> {
+    *sum = sum.checked_add(amount).ok_or(Error::Overflow)?;
+    Ok(())
+}
+
+// Describes what happened to a transaction, for the histories of the clients.
+fn action(previous: Option<&Transaction>, trans: &Transaction) -> &'static str {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return trans.kind.name(),
+    };
+    if previous.status == trans.status {
+        return match trans.status {
+            Status::Refunded => "refund",
+            _ if !previous.cleared && trans.cleared => "clearing",
+            _ => "update",
+        };
+    }
+    match trans.status {
+        Status::Open => "reopen",
+        Status::Pending => "dispute",
+        Status::Resolved => "resolve",
+        Status::Chargeback => "chargeback",
+        Status::Authorized => "authorization",
+        Status::Captured => "capture",
+        Status::Released => "release",
+        Status::Represented => "representment",
+        Status::Refunded => "refund",
+        Status::Reversed => "reversal",
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub(crate) enum Status {
     Open,
     Pending,
     Resolved,
     Chargeback,
+    Authorized,
+    Captured,
+    Released,
+    Represented,
+    Refunded,
+    Reversed,
+}
+
+impl Status {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        let status = match name {
+            "open" => Status::Open,
+            "pending" => Status::Pending,
+            "resolved" => Status::Resolved,
+            "chargeback" => Status::Chargeback,
+            "authorized" => Status::Authorized,
+            "captured" => Status::Captured,
+            "released" => Status::Released,
+            "represented" => Status::Represented,
+            "refunded" => Status::Refunded,
+            "reversed" => Status::Reversed,
+            _ => return None,
+        };
+        Some(status)
+    }
+}
+
+/// When the invariants of the accounts are checked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CheckMode {
+    Record,
+    End,
+}
+
+/// What happens to disputes that are still pending when they expire.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ExpiryPolicy {
+    Resolve,
+    Chargeback,
+}
+
+/// Expiry settles disputes that have been pending longer than its window.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Expiry {
+    pub(crate) window: Window,
+    pub(crate) policy: ExpiryPolicy,
 }
 
-#[derive(Debug, Clone)]
-struct Transaction {
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) enum Kind {
+    Deposit,
+    Withdrawal,
+    Transfer,
+    Authorization,
+    Adjustment(Bucket),
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Deposit => "deposit",
+            Kind::Withdrawal => "withdrawal",
+            Kind::Transfer => "transfer",
+            Kind::Authorization => "authorization",
+            Kind::Adjustment(_) => "adjustment",
+        }
+    }
+
+    // Adjustments are never carried over, so they aren't parsed.
+    fn parse(name: &str) -> Option<Self> {
+        let kind = match name {
+            "deposit" => Kind::Deposit,
+            "withdrawal" => Kind::Withdrawal,
+            "transfer" => Kind::Transfer,
+            "authorization" => Kind::Authorization,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// Part of a balance an adjustment applies to.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Bucket {
+    #[default]
+    Available,
+    Held,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Transaction {
     id: u32,
+    client: u16,
+    kind: Kind,
+    currency: String,
+    amount: Decimal,
+    status: Status,
+    destination: Option<u16>,
+    created: Moment,
+    // Whether the funds are past the clearing period.
+    cleared: bool,
+    // When the dispute was raised and by which client.
+    dispute: Option<(Moment, u16)>,
+    history: Vec<(Moment, Status)>,
+    // Amount returned by refunds so far.
+    refunded: Decimal,
+}
+
+/// Row of the opening balances file, in the schema of the output.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct OpeningBalance {
+    client: u16,
+    #[serde(default)]
+    currency: Option<String>,
+    available: Decimal,
+    held: Decimal,
+    #[serde(default)]
+    uncleared: Option<Decimal>,
+    total: Decimal,
+    locked: bool,
+}
+
+impl OpeningBalance {
+    fn currency(&self) -> String {
+        match self.currency.as_deref().map(str::trim) {
+            Some(currency) if !currency.is_empty() => currency.to_uppercase(),
+            _ => DEFAULT_CURRENCY.to_string(),
+        }
+    }
+
+    fn uncleared(&self) -> Decimal {
+        self.uncleared.unwrap_or_default()
+    }
+
+    // Rows must add up, and only available funds may be negative.
+    fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Error::InvalidOpening(self.client, reason.to_string());
+        let parts = self
+            .available
+            .checked_add(self.held)
+            .and_then(|sum| sum.checked_add(self.uncleared()))
+            .ok_or(Error::Overflow)?;
+        if parts != self.total {
+            return Err(invalid("total is not available + held + uncleared"));
+        }
+        if self.held < Decimal::ZERO || self.uncleared() < Decimal::ZERO {
+            return Err(invalid("held and uncleared funds can't be negative"));
+        }
+        Ok(())
+    }
+}
+
+/// Loads and validates the opening balances of a file.
+pub(crate) fn opening_balances_from_path(file: &str) -> Result<Vec<OpeningBalance>> {
+    let rdr = CsvReader::from_path(file).map_err(|_| Error::InvalidData)?;
+    opening_balances_from_csv(rdr)
+}
+
+#[cfg(test)]
+pub(crate) fn opening_balances_from_reader<R: io::Read>(rdr: R) -> Result<Vec<OpeningBalance>> {
+    opening_balances_from_csv(CsvReader::from_reader(rdr))
+}
+
+fn opening_balances_from_csv<R: io::Read>(mut rdr: CsvReader<R>) -> Result<Vec<OpeningBalance>> {
+    let mut rows = Vec::new();
+    for result in rdr.deserialize() {
+        let row: OpeningBalance = result.map_err(|_| Error::InvalidData)?;
+        row.validate()?;
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Row of the carried transactions file, for transactions that can still change in the next run.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct CarriedTransaction {
+    tx: u32,
+    client: u16,
+    kind: String,
+    currency: String,
     amount: Decimal,
+    refunded: Decimal,
     status: Status,
+    destination: Option<u16>,
+    cleared: bool,
+    timestamp: Option<u64>,
+    // Client that raised the pending dispute and when.
+    disputed_by: Option<u16>,
+    dispute_timestamp: Option<u64>,
+}
+
+/// Row of the archived ids file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct ArchivedTransaction {
+    tx: u32,
+}
+
+/// Row of the carried locks file, with every lock an account had.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct CarriedLock {
+    client: u16,
+    // Chargeback that caused the lock.
+    tx: Option<u32>,
+    timestamp: Option<u64>,
+    reason: String,
+    currency: String,
+    amount: Decimal,
+    lifted: bool,
+    lifted_timestamp: Option<u64>,
+}
+
+/// Lock put on an account, by a chargeback or by the processor itself.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Lock {
+    // Chargeback that caused the lock.
+    pub(crate) tx: Option<u32>,
+    pub(crate) at: Moment,
+    pub(crate) reason: String,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+    // When the lock was lifted.
+    pub(crate) lifted: Option<Moment>,
+}
+
+/// Row of the locked accounts output.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LockRow {
+    pub(crate) client: u16,
+    pub(crate) tx: Option<u32>,
+    pub(crate) position: u64,
+    pub(crate) timestamp: Option<u64>,
+    pub(crate) reason: String,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+}
+
+/// Row of the transactions output.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TransactionRow {
+    pub(crate) tx: u32,
+    pub(crate) client: u16,
+    pub(crate) kind: &'static str,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+    pub(crate) refunded: Decimal,
+    pub(crate) status: Status,
+    pub(crate) destination: Option<u16>,
+    pub(crate) position: u64,
+}
+
+/// Row of the pending disputes output.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DisputeRow {
+    pub(crate) tx: u32,
+    // Client that raised the dispute.
+    pub(crate) client: u16,
+    pub(crate) kind: &'static str,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+    pub(crate) position: u64,
+    pub(crate) timestamp: Option<u64>,
 }
 
 impl Transaction {
-    fn new(id: u32, amount: Decimal) -> Self {
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Position the pending dispute of the transaction was raised at, if it has one.
+    pub(crate) fn disputed_at(&self) -> Option<u64> {
+        match (&self.status, self.dispute) {
+            (Status::Pending, Some((since, _))) => Some(since.position),
+            _ => None,
+        }
+    }
+
+    /// When the transaction was made, if its funds haven't cleared.
+    pub(crate) fn uncleared_since(&self) -> Option<Moment> {
+        (!self.cleared).then_some(self.created)
+    }
+
+    fn row(&self) -> TransactionRow {
+        TransactionRow {
+            tx: self.id,
+            client: self.client,
+            kind: self.kind.name(),
+            currency: self.currency.clone(),
+            amount: self.amount,
+            refunded: self.refunded,
+            status: self.status.clone(),
+            destination: self.destination,
+            position: self.created.position,
+        }
+    }
+
+    // Client holding the funds of the transaction, if any, and the amount it added to the
+    // totals of the clients.
+    fn effects(&self) -> (Option<u16>, Decimal) {
+        let holder = match (&self.kind, &self.status) {
+            (_, Status::Pending) => self.dispute.map(|(_, client)| client),
+            (Kind::Authorization, Status::Authorized) => Some(self.client),
+            (Kind::Adjustment(Bucket::Held), _) => Some(self.client),
+            _ => None,
+        };
+        let kept = self.amount - self.refunded;
+        let flow = match (&self.kind, &self.status) {
+            (Kind::Deposit, Status::Reversed) => Decimal::ZERO,
+            (Kind::Deposit, _) => kept,
+            (Kind::Withdrawal, _) => -kept,
+            (Kind::Authorization, Status::Captured) => -self.amount,
+            (Kind::Authorization, _) => Decimal::ZERO,
+            (Kind::Adjustment(_), _) => self.amount,
+            (Kind::Transfer, _) => Decimal::ZERO,
+        };
+        // Charged back funds leave the clients, except those of transfers that go back to the sender.
+        let chargeback = match (&self.kind, &self.status) {
+            (Kind::Transfer, _) => Decimal::ZERO,
+            (_, Status::Chargeback) => self.amount,
+            _ => Decimal::ZERO,
+        };
+        (holder, flow - chargeback)
+    }
+
+    // Held and total funds of the transaction by client, that are set aside with the opening
+    // balances when it's archived.
+    fn aside(&self) -> Vec<(u16, Decimal, Decimal)> {
+        let (holder, flow) = self.effects();
+        let mut funds = vec![(self.client, Decimal::ZERO, flow)];
+        if let Some(client) = holder {
+            funds.push((client, self.amount, Decimal::ZERO));
+        }
+        funds
+    }
+
+    fn new(id: u32, client: u16, kind: Kind, currency: &str, amount: Decimal) -> Self {
         Self {
             id,
+            client,
+            kind,
+            currency: currency.to_string(),
             amount,
             status: Status::Open,
+            destination: None,
+            created: Moment::default(),
+            cleared: true,
+            dispute: None,
+            history: Vec::new(),
+            refunded: Decimal::ZERO,
         }
     }
 }
@@ -0,0 +1,45 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Moment;
+
+/// Entry of the audit trail, for actions the processor takes on its own
+/// and for operations that must always be traceable.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct AuditEntry {
+    pub(crate) position: u64,
+    pub(crate) timestamp: Option<u64>,
+    pub(crate) client: u16,
+    pub(crate) tx: u32,
+    pub(crate) action: String,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+    pub(crate) reason: String,
+    // Who made the change, for manual operations.
+    pub(crate) operator: String,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(at: Moment, client: u16, tx: u32, action: &str) -> Self {
+        Self {
+            position: at.position,
+            timestamp: at.timestamp,
+            client,
+            tx,
+            action: action.to_string(),
+            currency: String::new(),
+            amount: Decimal::ZERO,
+            reason: String::new(),
+            operator: String::new(),
+        }
+    }
+}
+
+/// Invariant found not to hold after a record, or at the end of the run.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Violation {
+    pub(crate) position: u64,
+    pub(crate) tx: Option<u32>,
+    pub(crate) invariant: String,
+    pub(crate) detail: String,
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Moment of a record in the input: its position and its timestamp if it has one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Moment {
+    pub(crate) position: u64,
+    // Seconds since the unix epoch.
+    pub(crate) timestamp: Option<u64>,
+}
+
+/// Window is a span of records or of seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Window {
+    Records(u64),
+    Seconds(u64),
+}
+
+impl Window {
+    /// Picks the window from a records and a seconds option, exactly one must be set.
+    pub(crate) fn from_options(records: Option<u64>, seconds: Option<u64>) -> Option<Self> {
+        match (records, seconds) {
+            (Some(records), None) => Some(Window::Records(records)),
+            (None, Some(seconds)) => Some(Window::Seconds(seconds)),
+            _ => None,
+        }
+    }
+
+    /// Whether `later` falls within the window that starts at `earlier`.
+    /// Returns `None` for a window in seconds when either moment has no timestamp.
+    pub(crate) fn contains(&self, earlier: Moment, later: Moment) -> Option<bool> {
+        match self {
+            Window::Records(records) => {
+                Some(later.position.saturating_sub(earlier.position) < *records)
+            }
+            Window::Seconds(seconds) => {
+                let elapsed = later.timestamp?.saturating_sub(earlier.timestamp?);
+                Some(elapsed < *seconds)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Window::Records(records) => write!(f, "{} records", records),
+            Window::Seconds(seconds) => write!(f, "{} seconds", seconds),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::processor::{Record, Type};
+
+// Position, tx and amount of deposits just under the threshold.
+type Cluster = VecDeque<(u64, u32, Decimal)>;
+
+/// Entry of the compliance report.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Entry {
+    pub(crate) position: u64,
+    pub(crate) client: u16,
+    pub(crate) tx: u32,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+    pub(crate) reason: String,
+}
+
+/// Compliance reports transactions above the reporting threshold and
+/// clusters of deposits just under it by the same client.
+#[derive(Debug)]
+pub(crate) struct Compliance {
+    threshold: Decimal,
+    // Deposits at or above `threshold * (1 - margin)` are just under the threshold.
+    margin: Decimal,
+    // Number of deposits just under the threshold that make up a cluster.
+    count: usize,
+    // Number of records a cluster may span.
+    window: u64,
+    recent: HashMap<(u16, String), Cluster>,
+    entries: Vec<Entry>,
+}
+
+impl Default for Compliance {
+    fn default() -> Self {
+        Self {
+            threshold: Decimal::new(10_000, 0),
+            margin: Decimal::new(1, 1),
+            count: 3,
+            window: 100,
+            recent: HashMap::new(),
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Compliance {
+    /// Uses the thresholds in the config, or the defaults for those that aren't set.
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let mut compliance = Self::default();
+        if let Some(threshold) = config.reporting_threshold {
+            compliance.set_threshold(threshold);
+        }
+        compliance.set_structuring(
+            config.structuring_margin.unwrap_or(compliance.margin),
+            config.structuring_count.unwrap_or(compliance.count),
+            config.structuring_window.unwrap_or(compliance.window),
+        );
+        compliance
+    }
+
+    pub(crate) fn set_threshold(&mut self, threshold: Decimal) {
+        self.threshold = threshold;
+    }
+
+    pub(crate) fn set_structuring(&mut self, margin: Decimal, count: usize, window: u64) {
+        self.margin = margin;
+        self.count = count;
+        self.window = window;
+    }
+
+    pub(crate) fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Looks at a record that was applied.
+    pub(crate) fn observe(&mut self, record: &Record, position: u64) -> Result<()> {
+        let currency = record.currency();
+        let amount = match (record.ty(), record.amount()) {
+            (Type::Deposit | Type::Withdrawal | Type::Transfer, Some(amount)) => amount,
+            _ => return Ok(()),
+        };
+        let entry = |reason: String| Entry {
+            position,
+            client: record.client(),
+            tx: record.tx(),
+            currency: currency.to_string(),
+            amount,
+            reason,
+        };
+
+        if amount >= self.threshold {
+            let kind = match record.ty() {
+                Type::Deposit => "deposit",
+                Type::Withdrawal => "withdrawal",
+                _ => "transfer",
+            };
+            self.entries
+                .push(entry(format!("{} at or above {}", kind, self.threshold)));
+            return Ok(());
+        }
+
+        let floor = self.threshold * (Decimal::ONE - self.margin);
+        if !matches!(record.ty(), Type::Deposit) || amount < floor {
+            return Ok(());
+        }
+        let recent = self
+            .recent
+            .entry((record.client(), currency.to_string()))
+            .or_default();
+        recent.push_back((position, record.tx(), amount));
+        while let Some((at, _, _)) = recent.front() {
+            if position - at < self.window {
+                break;
+            }
+            recent.pop_front();
+        }
+        if recent.len() >= self.count {
+            let total = recent
+                .iter()
+                .try_fold(Decimal::ZERO, |total, (_, _, amount)| {
+                    total.checked_add(*amount)
+                })
+                .ok_or(Error::Overflow)?;
+            let txs: Vec<String> = recent.iter().map(|(_, tx, _)| tx.to_string()).collect();
+            recent.clear();
+            let mut entry = entry(format!(
+                "possible structuring: deposits {} just under {}",
+                txs.join(" "),
+                self.threshold
+            ));
+            entry.amount = total;
+            self.entries.push(entry);
+        }
+        Ok(())
+    }
+}
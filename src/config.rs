@@ -0,0 +1,233 @@
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+use crate::account::{CheckMode, ExpiryPolicy, Status};
+use crate::clock::Window;
+use crate::error::{Error, Result};
+use crate::fx::Rounding;
+use crate::statement::{Format, Statement};
+
+/// Config holds the options of a run, parsed from the command line.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub(crate) input: Option<String>,
+    // Path to a csv file with columns from, to, rate.
+    pub(crate) fx_rates: Option<String>,
+    // When set, accounts are reported as a single row in this currency.
+    pub(crate) base_currency: Option<String>,
+    pub(crate) rounding: Rounding,
+    // Path to a csv file with columns type, method, value, min_amount.
+    pub(crate) fee_schedule: Option<String>,
+    pub(crate) house_account: Option<u16>,
+    // Path where the fee postings are written.
+    pub(crate) fees_out: Option<String>,
+    // Path to a csv file with columns client, limit.
+    pub(crate) credit_limits: Option<String>,
+    pub(crate) default_credit_limit: Decimal,
+    // Path to a csv file with columns client, tier, window, max_count, max_amount.
+    pub(crate) velocity_rules: Option<String>,
+    // Path to a csv file with columns client, tier.
+    pub(crate) client_tiers: Option<String>,
+    // Path to a csv file with columns rule, action, limit, window.
+    pub(crate) risk_rules: Option<String>,
+    // Path where records flagged by the risk rules are written.
+    pub(crate) flags_out: Option<String>,
+    // Path where the compliance report is written.
+    pub(crate) compliance_out: Option<String>,
+    pub(crate) reporting_threshold: Option<Decimal>,
+    pub(crate) structuring_margin: Option<Decimal>,
+    pub(crate) structuring_count: Option<usize>,
+    pub(crate) structuring_window: Option<u64>,
+    // Paths to csv files with a client column.
+    pub(crate) blocklist: Option<String>,
+    pub(crate) allowlist: Option<String>,
+    // Whether accounts of blocked clients are reported as locked.
+    pub(crate) lock_blocked: bool,
+    // Seconds records are buffered for to put them in timestamp order.
+    pub(crate) reorder_window: Option<u64>,
+    pub(crate) dispute_window: Option<Window>,
+    pub(crate) dispute_expiry: Option<Window>,
+    pub(crate) expiry_policy: Option<ExpiryPolicy>,
+    // Path where the audit trail is written.
+    pub(crate) audit_out: Option<String>,
+    // Deposits only become available after this window.
+    pub(crate) clearing_period: Option<Window>,
+    // Whether accounts are unlocked when a chargeback is reversed.
+    pub(crate) unlock_on_representment: bool,
+    // Path where the journal of the ledger is written.
+    pub(crate) journal_out: Option<String>,
+    // Audit mode, checking the invariants of the accounts.
+    pub(crate) check_invariants: Option<CheckMode>,
+    // Whether processing stops at the first invariant violation instead of reporting it.
+    pub(crate) abort_on_violation: bool,
+    // Path where invariant violations are written.
+    pub(crate) violations_out: Option<String>,
+    // Statement written instead of the accounts, with the `statement` command.
+    pub(crate) statement: Option<Statement>,
+    // Path where the stored transactions are written, only those in `transaction_statuses` if any.
+    pub(crate) transactions_out: Option<String>,
+    pub(crate) transaction_statuses: Vec<Status>,
+    // Path where the pending disputes are written.
+    pub(crate) disputes_out: Option<String>,
+    // Path where the locked accounts are written.
+    pub(crate) locked_out: Option<String>,
+    // Path of the balances accounts start with.
+    pub(crate) opening_balances: Option<String>,
+    // Directory the close of the run is written to.
+    pub(crate) close: Option<String>,
+    // Directory of the close of the previous run, that accounts start from.
+    pub(crate) carry_forward: Option<String>,
+    // Database file the accounts and transactions are stored in instead of memory.
+    pub(crate) store: Option<String>,
+}
+
+impl Config {
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut config = Self::default();
+        let mut client = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "statement" if config.input.is_none() && config.statement.is_none() => {
+                    config.statement = Some(Statement::default())
+                }
+                "--client" => client = Some(parse(&arg, &mut args)?),
+                "--from" => statement(&mut config, &arg)?.from = Some(parse(&arg, &mut args)?),
+                "--to" => statement(&mut config, &arg)?.to = Some(parse(&arg, &mut args)?),
+                "--format" => {
+                    let name = value(&arg, &mut args)?;
+                    statement(&mut config, &arg)?.format = match name.as_str() {
+                        "csv" => Format::Csv,
+                        "json" => Format::Json,
+                        _ => return Err(invalid(&arg, &name)),
+                    };
+                }
+                "--fx-rates" => config.fx_rates = Some(value(&arg, &mut args)?),
+                "--base-currency" => {
+                    config.base_currency = Some(value(&arg, &mut args)?.to_uppercase())
+                }
+                "--rounding-dp" => config.rounding.dp = parse(&arg, &mut args)?,
+                "--rounding" => {
+                    let name = value(&arg, &mut args)?;
+                    config.rounding.strategy =
+                        Rounding::parse_strategy(&name).ok_or_else(|| invalid(&arg, &name))?;
+                }
+                "--fee-schedule" => config.fee_schedule = Some(value(&arg, &mut args)?),
+                "--house-account" => config.house_account = Some(parse(&arg, &mut args)?),
+                "--fees-out" => config.fees_out = Some(value(&arg, &mut args)?),
+                "--credit-limits" => config.credit_limits = Some(value(&arg, &mut args)?),
+                "--default-credit-limit" => config.default_credit_limit = parse(&arg, &mut args)?,
+                "--velocity-rules" => config.velocity_rules = Some(value(&arg, &mut args)?),
+                "--client-tiers" => config.client_tiers = Some(value(&arg, &mut args)?),
+                "--risk-rules" => config.risk_rules = Some(value(&arg, &mut args)?),
+                "--flags-out" => config.flags_out = Some(value(&arg, &mut args)?),
+                "--compliance-out" => config.compliance_out = Some(value(&arg, &mut args)?),
+                "--reporting-threshold" => {
+                    config.reporting_threshold = Some(parse(&arg, &mut args)?)
+                }
+                "--structuring-margin" => config.structuring_margin = Some(parse(&arg, &mut args)?),
+                "--structuring-count" => config.structuring_count = Some(parse(&arg, &mut args)?),
+                "--structuring-window" => config.structuring_window = Some(parse(&arg, &mut args)?),
+                "--blocklist" => config.blocklist = Some(value(&arg, &mut args)?),
+                "--allowlist" => config.allowlist = Some(value(&arg, &mut args)?),
+                "--lock-blocked" => config.lock_blocked = true,
+                "--reorder-window" => config.reorder_window = Some(parse(&arg, &mut args)?),
+                "--dispute-window" => {
+                    config.dispute_window = Some(Window::Records(parse(&arg, &mut args)?))
+                }
+                "--dispute-window-secs" => {
+                    config.dispute_window = Some(Window::Seconds(parse(&arg, &mut args)?))
+                }
+                "--dispute-expiry" => {
+                    config.dispute_expiry = Some(Window::Records(parse(&arg, &mut args)?))
+                }
+                "--dispute-expiry-secs" => {
+                    config.dispute_expiry = Some(Window::Seconds(parse(&arg, &mut args)?))
+                }
+                "--expiry-policy" => {
+                    let name = value(&arg, &mut args)?;
+                    config.expiry_policy = match name.as_str() {
+                        "resolve" => Some(ExpiryPolicy::Resolve),
+                        "chargeback" => Some(ExpiryPolicy::Chargeback),
+                        _ => return Err(invalid(&arg, &name)),
+                    };
+                }
+                "--clearing-period" => {
+                    config.clearing_period = Some(Window::Records(parse(&arg, &mut args)?))
+                }
+                "--clearing-period-secs" => {
+                    config.clearing_period = Some(Window::Seconds(parse(&arg, &mut args)?))
+                }
+                "--unlock-on-representment" => config.unlock_on_representment = true,
+                "--check-invariants" => {
+                    let name = value(&arg, &mut args)?;
+                    config.check_invariants = match name.as_str() {
+                        "record" => Some(CheckMode::Record),
+                        "end" => Some(CheckMode::End),
+                        _ => return Err(invalid(&arg, &name)),
+                    };
+                }
+                "--abort-on-violation" => config.abort_on_violation = true,
+                "--violations-out" => config.violations_out = Some(value(&arg, &mut args)?),
+                "--transactions-out" => config.transactions_out = Some(value(&arg, &mut args)?),
+                "--transaction-status" => {
+                    let names = value(&arg, &mut args)?;
+                    for name in names.split(',') {
+                        let status = Status::parse(name.trim().to_lowercase().as_str())
+                            .ok_or_else(|| invalid(&arg, name))?;
+                        config.transaction_statuses.push(status);
+                    }
+                }
+                "--opening-balances" => config.opening_balances = Some(value(&arg, &mut args)?),
+                "--close" => config.close = Some(value(&arg, &mut args)?),
+                "--carry-forward" => config.carry_forward = Some(value(&arg, &mut args)?),
+                "--store" => config.store = Some(value(&arg, &mut args)?),
+                "--locked-out" => config.locked_out = Some(value(&arg, &mut args)?),
+                "--disputes-out" => config.disputes_out = Some(value(&arg, &mut args)?),
+                "--journal-out" => config.journal_out = Some(value(&arg, &mut args)?),
+                "--audit-out" => config.audit_out = Some(value(&arg, &mut args)?),
+                _ if arg.starts_with("--") => {
+                    return Err(Error::InvalidConfig(format!("unknown option {}", arg)))
+                }
+                _ => config.input = Some(arg),
+            }
+        }
+        match (&mut config.statement, client) {
+            (Some(statement), Some(client)) => statement.client = client,
+            (Some(_), None) => {
+                return Err(Error::InvalidConfig(
+                    "missing --client for statement".to_string(),
+                ))
+            }
+            (None, Some(_)) => return Err(only_statement("--client")),
+            (None, None) => {}
+        }
+        Ok(config)
+    }
+}
+
+// Returns the statement an option of the `statement` command applies to.
+fn statement<'a>(config: &'a mut Config, option: &str) -> Result<&'a mut Statement> {
+    config
+        .statement
+        .as_mut()
+        .ok_or_else(|| only_statement(option))
+}
+
+fn only_statement(option: &str) -> Error {
+    Error::InvalidConfig(format!("{} is only used by the statement command", option))
+}
+
+// Returns the value following an option.
+fn value(option: &str, args: &mut impl Iterator<Item = String>) -> Result<String> {
+    args.next()
+        .ok_or_else(|| Error::InvalidConfig(format!("missing value for {}", option)))
+}
+
+fn parse<T: FromStr>(option: &str, args: &mut impl Iterator<Item = String>) -> Result<T> {
+    let buf = value(option, args)?;
+    buf.parse().map_err(|_| invalid(option, &buf))
+}
+
+fn invalid(option: &str, value: &str) -> Error {
+    Error::InvalidConfig(format!("invalid value {} for {}", value, option))
+}
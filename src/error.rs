@@ -12,4 +12,37 @@ pub enum Error {
     Overflow,
     #[error("tx already exists")]
     TxExists,
+    #[error("currency does not match the transaction")]
+    CurrencyMismatch,
+    #[error("no exchange rate from {0} to {1}")]
+    MissingRate(String, String),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("velocity limit exceeded: {0}")]
+    VelocityLimit(String),
+    #[error("rejected by {0}: {1}")]
+    Rejected(String, String),
+    #[error("client {0} is blocked")]
+    BlockedClient(u16),
+    #[error("client {0} is not allowed")]
+    ClientNotAllowed(u16),
+    #[error("tx {0} arrived after the reorder window")]
+    LateRecord(u32),
+    #[error("tx {0} can no longer be disputed")]
+    DisputeWindowClosed(u32),
+    #[error("tx {0} can't be refunded: {1}")]
+    InvalidRefund(u32, String),
+    #[error("tx {0} can't be reversed: {1}")]
+    InvalidReversal(u32, String),
+    #[error("invalid adjustment {0}: {1}")]
+    InvalidAdjustment(u32, String),
+    #[error("invariant violated: {0}")]
+    InvariantViolation(String),
+    #[error("invalid opening balance of client {0}: {1}")]
+    InvalidOpening(u16, String),
+    #[error("journal entry of tx {0} doesn't balance")]
+    Unbalanced(u32),
+    #[cfg(feature = "sqlite")]
+    #[error("store error: {0}")]
+    Store(String),
 }
@@ -0,0 +1,112 @@
+use csv::Reader as CsvReader;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+
+use crate::error::{Error, Result};
+
+/// Client id of the account credited with fees, unless configured otherwise.
+pub(crate) const DEFAULT_HOUSE_ACCOUNT: u16 = u16::MAX;
+
+/// Operations that can be charged a fee.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FeeType {
+    Withdrawal,
+    Transfer,
+    Dispute,
+    Chargeback,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Method {
+    Flat,
+    Percentage,
+}
+
+/// Row of the fee schedule file.
+/// A fee applies to amounts of at least `min_amount`, so several rows
+/// for the same type make up a tiered fee.
+#[derive(Debug, Clone, Deserialize)]
+struct Tier {
+    #[serde(rename = "type")]
+    ty: FeeType,
+    method: Method,
+    value: Decimal,
+    #[serde(default)]
+    min_amount: Option<Decimal>,
+}
+
+impl Tier {
+    fn min_amount(&self) -> Decimal {
+        self.min_amount.unwrap_or_default()
+    }
+}
+
+/// Schedule computes the fee charged for an operation.
+#[derive(Debug, Default)]
+pub(crate) struct Schedule {
+    inner: HashMap<FeeType, Vec<Tier>>,
+}
+
+impl Schedule {
+    pub(crate) fn from_path(file: &str) -> Result<Self> {
+        let rdr = CsvReader::from_path(file).map_err(|_| Error::InvalidData)?;
+        Self::from_csv(rdr)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_reader<R: io::Read>(rdr: R) -> Result<Self> {
+        Self::from_csv(CsvReader::from_reader(rdr))
+    }
+
+    fn from_csv<R: io::Read>(mut rdr: CsvReader<R>) -> Result<Self> {
+        let mut schedule = Self::default();
+        for result in rdr.deserialize() {
+            let tier: Tier = result.map_err(|_| Error::InvalidData)?;
+            if tier.value < Decimal::ZERO {
+                return Err(Error::InvalidData);
+            }
+            schedule.inner.entry(tier.ty).or_default().push(tier);
+        }
+        for tiers in schedule.inner.values_mut() {
+            tiers.sort_by_key(|tier| tier.min_amount());
+        }
+        Ok(schedule)
+    }
+
+    /// Fee for an operation on the given amount, using the highest tier the amount reaches.
+    pub(crate) fn fee(&self, ty: FeeType, amount: Decimal) -> Result<Decimal> {
+        let tier = match self
+            .inner
+            .get(&ty)
+            .and_then(|tiers| tiers.iter().rev().find(|tier| tier.min_amount() <= amount))
+        {
+            Some(tier) => tier,
+            None => return Ok(Decimal::ZERO),
+        };
+        let fee = match tier.method {
+            Method::Flat => tier.value,
+            Method::Percentage => amount
+                .checked_mul(tier.value)
+                .and_then(|fee| fee.checked_div(Decimal::ONE_HUNDRED))
+                .ok_or(Error::Overflow)?
+                .round_dp(4),
+        };
+        Ok(fee)
+    }
+}
+
+/// Posting of a fee charged to a client and credited to the house account.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct FeePosting {
+    pub(crate) tx: u32,
+    pub(crate) client: u16,
+    #[serde(rename = "type")]
+    pub(crate) ty: FeeType,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+    pub(crate) reversed: bool,
+}
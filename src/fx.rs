@@ -0,0 +1,103 @@
+use csv::Reader as CsvReader;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+
+use crate::error::{Error, Result};
+
+/// Row of the rates file: one unit of `from` is worth `rate` units of `to`.
+#[derive(Debug, Deserialize)]
+struct Rate {
+    from: String,
+    to: String,
+    rate: Decimal,
+}
+
+/// Rates is a table of exchange rates between currencies.
+#[derive(Debug, Default)]
+pub(crate) struct Rates {
+    inner: HashMap<(String, String), Decimal>,
+}
+
+impl Rates {
+    pub(crate) fn from_path(file: &str) -> Result<Self> {
+        let rdr = CsvReader::from_path(file).map_err(|_| Error::InvalidData)?;
+        Self::from_csv(rdr)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_reader<R: io::Read>(rdr: R) -> Result<Self> {
+        Self::from_csv(CsvReader::from_reader(rdr))
+    }
+
+    fn from_csv<R: io::Read>(mut rdr: CsvReader<R>) -> Result<Self> {
+        let mut rates = Self::default();
+        for result in rdr.deserialize() {
+            let rate: Rate = result.map_err(|_| Error::InvalidData)?;
+            if rate.rate <= Decimal::ZERO {
+                return Err(Error::InvalidData);
+            }
+            rates.inner.insert(
+                (
+                    rate.from.trim().to_uppercase(),
+                    rate.to.trim().to_uppercase(),
+                ),
+                rate.rate,
+            );
+        }
+        Ok(rates)
+    }
+
+    /// Converts an amount between currencies.
+    /// If there is no direct rate, the inverse of the opposite rate is used.
+    pub(crate) fn convert(&self, amount: Decimal, from: &str, to: &str) -> Result<Decimal> {
+        if from == to {
+            return Ok(amount);
+        }
+        if let Some(rate) = self.inner.get(&(from.to_string(), to.to_string())) {
+            return amount.checked_mul(*rate).ok_or(Error::Overflow);
+        }
+        if let Some(rate) = self.inner.get(&(to.to_string(), from.to_string())) {
+            return amount.checked_div(*rate).ok_or(Error::Overflow);
+        }
+        Err(Error::MissingRate(from.to_string(), to.to_string()))
+    }
+}
+
+/// Rounding applied to converted amounts.
+#[derive(Debug, Clone, Copy)]
+pub struct Rounding {
+    pub(crate) dp: u32,
+    pub(crate) strategy: RoundingStrategy,
+}
+
+impl Rounding {
+    /// Rounds an amount to `dp` decimal places, padding it with zeros so it always has that many.
+    pub(crate) fn round(&self, amount: Decimal) -> Decimal {
+        let mut rounded = amount.round_dp_with_strategy(self.dp, self.strategy);
+        rounded.rescale(self.dp);
+        rounded
+    }
+
+    pub(crate) fn parse_strategy(name: &str) -> Option<RoundingStrategy> {
+        let strategy = match name {
+            "half-even" => RoundingStrategy::MidpointNearestEven,
+            "half-up" => RoundingStrategy::MidpointAwayFromZero,
+            "half-down" => RoundingStrategy::MidpointTowardZero,
+            "up" => RoundingStrategy::AwayFromZero,
+            "down" => RoundingStrategy::ToZero,
+            _ => return None,
+        };
+        Some(strategy)
+    }
+}
+
+impl Default for Rounding {
+    fn default() -> Self {
+        Self {
+            dp: 4,
+            strategy: RoundingStrategy::MidpointNearestEven,
+        }
+    }
+}
@@ -1,10 +1,15 @@
-use crate::account::Account;
+use crate::account::{Account, DEFAULT_CURRENCY};
 use csv::{Reader as CsvReader, Writer as CsvWriter};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::fs::File;
 use std::io;
 use std::io::Stdout;
+use std::io::Write;
 
 use crate::error::{Error, Result};
+use crate::fx::{Rates, Rounding};
 use crate::processor::Record;
 
 pub struct Reader<T = File> {
@@ -28,21 +33,141 @@ impl Reader {
     }
 }
 
-pub struct Writer {
-    inner: CsvWriter<Stdout>,
+/// Reads the rows of a csv file.
+pub(crate) fn read_csv<T: DeserializeOwned>(file: &str) -> Result<Vec<T>> {
+    let mut rdr = CsvReader::from_path(file).map_err(|_| Error::InvalidData)?;
+    let mut rows = Vec::new();
+    for result in rdr.deserialize() {
+        rows.push(result.map_err(|_| Error::InvalidData)?);
+    }
+    Ok(rows)
+}
+
+/// Writes rows to a csv file.
+pub(crate) fn write_csv<S: Serialize>(file: &str, rows: impl IntoIterator<Item = S>) -> Result<()> {
+    stream_csv(file, |f| rows.into_iter().try_for_each(f))
+}
+
+/// Writes the rows of a scan to a csv file as they're visited.
+pub(crate) fn stream_csv<S: Serialize>(
+    file: &str,
+    scan: impl FnOnce(&mut dyn FnMut(S) -> Result<()>) -> Result<()>,
+) -> Result<()> {
+    let mut wtr = CsvWriter::from_path(file).map_err(|_| Error::InvalidData)?;
+    scan(&mut |row| wtr.serialize(row).map_err(|_| Error::InvalidData))?;
+    wtr.flush().map_err(|_| Error::InvalidData)?;
+    Ok(())
+}
+
+/// Output row of an account's balance in one currency.
+#[derive(Serialize)]
+struct Row<'a> {
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    available: Decimal,
+    held: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uncleared: Option<Decimal>,
+    total: Decimal,
+    locked: bool,
+}
+
+pub struct Writer<W: Write = Stdout> {
+    inner: CsvWriter<W>,
+    // Whether rows have a column for uncleared funds.
+    uncleared: bool,
 }
 
 impl Writer {
     pub(crate) fn new() -> Self {
         Self {
             inner: CsvWriter::from_writer(io::stdout()),
+            uncleared: false,
+        }
+    }
+}
+
+impl<W: Write> Writer<W> {
+    pub(crate) fn from_writer(wtr: W) -> Self {
+        Self {
+            inner: CsvWriter::from_writer(wtr),
+            uncleared: false,
+        }
+    }
+
+    pub(crate) fn set_uncleared(&mut self, uncleared: bool) {
+        self.uncleared = uncleared;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn into_inner(self) -> W {
+        self.inner.into_inner().ok().unwrap()
+    }
+
+    /// Writes a row per client and currency.
+    /// The currency column is only written if some balance is not in the default currency.
+    pub(crate) fn write(&mut self, data: &[Account]) -> Result<()> {
+        let multi_currency = data.iter().any(|account| {
+            account
+                .balances()
+                .iter()
+                .any(|(currency, _)| *currency != DEFAULT_CURRENCY)
+        });
+        for account in data {
+            for (currency, balance) in account.balances() {
+                let row = Row {
+                    client: account.client(),
+                    currency: multi_currency.then_some(currency),
+                    available: balance.available(),
+                    held: balance.held(),
+                    uncleared: self.uncleared.then_some(balance.uncleared()),
+                    total: balance.total(),
+                    locked: account.frozen(),
+                };
+                self.inner.serialize(row).map_err(|_| Error::InvalidData)?;
+            }
         }
+        Ok(())
     }
 
-    pub(crate) fn write(&mut self, data: Vec<&Account>) -> Result<()> {
-        for d in data {
-            self.inner.serialize(d).map_err(|_| Error::InvalidData)?;
+    /// Writes a row per client with all balances converted to the base currency.
+    pub(crate) fn write_consolidated(
+        &mut self,
+        data: &[Account],
+        rates: &Rates,
+        base: &str,
+        rounding: Rounding,
+    ) -> Result<()> {
+        for account in data {
+            let mut row = Row {
+                client: account.client(),
+                currency: Some(base),
+                available: Decimal::ZERO,
+                held: Decimal::ZERO,
+                uncleared: self.uncleared.then_some(Decimal::ZERO),
+                total: Decimal::ZERO,
+                locked: account.frozen(),
+            };
+            for (currency, balance) in account.balances() {
+                let convert = |amount| rates.convert(amount, currency, base);
+                row.available = add(row.available, convert(balance.available())?)?;
+                row.held = add(row.held, convert(balance.held())?)?;
+                if let Some(uncleared) = row.uncleared {
+                    row.uncleared = Some(add(uncleared, convert(balance.uncleared())?)?);
+                }
+                row.total = add(row.total, convert(balance.total())?)?;
+            }
+            row.available = rounding.round(row.available);
+            row.held = rounding.round(row.held);
+            row.uncleared = row.uncleared.map(|uncleared| rounding.round(uncleared));
+            row.total = rounding.round(row.total);
+            self.inner.serialize(row).map_err(|_| Error::InvalidData)?;
         }
         Ok(())
     }
 }
+
+fn add(a: Decimal, b: Decimal) -> Result<Decimal> {
+    a.checked_add(b).ok_or(Error::Overflow)
+}
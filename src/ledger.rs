@@ -0,0 +1,137 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::clock::Moment;
+use crate::error::{Error, Result};
+use crate::store::Store;
+
+/// Book of the ledger that postings are made to.
+/// Client books make up the balances of the accounts, system books are
+/// the other side of money entering or leaving them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub(crate) enum Book {
+    Available(u16),
+    Held(u16),
+    Uncleared(u16),
+    // Money deposited from outside.
+    Funding,
+    // Money withdrawn to outside.
+    Payouts,
+    // Money in flight between two clients, by transfer or fee.
+    Transfers,
+    Fees,
+    // Money taken back by chargebacks.
+    ChargebackLoss,
+    Adjustments,
+    // Balances carried over from before the first record.
+    Opening,
+}
+
+impl Book {
+    /// Client the book belongs to, if it's a client book.
+    pub(crate) fn client(&self) -> Option<u16> {
+        match self {
+            Book::Available(client) | Book::Held(client) | Book::Uncleared(client) => Some(*client),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Book::Available(_) => "available",
+            Book::Held(_) => "held",
+            Book::Uncleared(_) => "uncleared",
+            Book::Funding => "funding",
+            Book::Payouts => "payouts",
+            Book::Transfers => "transfers",
+            Book::Fees => "fees",
+            Book::ChargebackLoss => "chargeback-loss",
+            Book::Adjustments => "adjustments",
+            Book::Opening => "opening",
+        }
+    }
+}
+
+/// Amount posted to a book, positive amounts add to the book.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Posting {
+    pub(crate) book: Book,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+}
+
+/// Journal entry of a transaction, its postings add up to zero in every currency.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Entry {
+    pub(crate) at: Moment,
+    // Transaction of the entry, opening balances have none.
+    pub(crate) tx: Option<u32>,
+    pub(crate) postings: Vec<Posting>,
+}
+
+/// Row of the journal output, one per posting.
+#[derive(Debug, Serialize)]
+pub(crate) struct JournalRow {
+    position: u64,
+    tx: Option<u32>,
+    book: &'static str,
+    client: Option<u16>,
+    currency: String,
+    amount: Decimal,
+}
+
+/// Records the postings of a transaction, which must balance, into its journal entry,
+/// adding them to the balances of their books. The ledger is kept by the store.
+pub(crate) fn record(
+    store: &mut dyn Store,
+    at: Moment,
+    tx: Option<u32>,
+    postings: Vec<Posting>,
+) -> Result<()> {
+    let mut sums: BTreeMap<&str, Decimal> = BTreeMap::new();
+    for posting in &postings {
+        let sum = sums.entry(&posting.currency).or_default();
+        *sum = sum.checked_add(posting.amount).ok_or(Error::Overflow)?;
+    }
+    if sums.values().any(|sum| !sum.is_zero()) {
+        return Err(Error::Unbalanced(tx.unwrap_or_default()));
+    }
+    // Balances are only put once they all add up.
+    let mut balances: BTreeMap<(Book, &str), Decimal> = BTreeMap::new();
+    for posting in &postings {
+        let key = (posting.book, posting.currency.as_str());
+        let balance = match balances.get(&key) {
+            Some(balance) => *balance,
+            None => store.balance(posting.book, &posting.currency)?,
+        };
+        let balance = balance.checked_add(posting.amount).ok_or(Error::Overflow)?;
+        balances.insert(key, balance);
+    }
+    for ((book, currency), balance) in balances {
+        store.put_balance(book, currency, balance)?;
+    }
+    store.put_entry(Entry { at, tx, postings })
+}
+
+/// Sum of the system books in a currency, the opposite of what clients hold.
+pub(crate) fn outside(store: &dyn Store, currency: &str) -> Result<Decimal> {
+    store
+        .system_balances(currency)?
+        .into_iter()
+        .try_fold(Decimal::ZERO, |sum, balance| {
+            sum.checked_add(balance).ok_or(Error::Overflow)
+        })
+}
+
+/// Rows of the journal output of an entry.
+pub(crate) fn rows(entry: Entry) -> impl Iterator<Item = JournalRow> {
+    entry.postings.into_iter().map(move |posting| JournalRow {
+        position: entry.at.position,
+        tx: entry.tx,
+        book: posting.book.name(),
+        client: posting.book.client(),
+        currency: posting.currency,
+        amount: posting.amount,
+    })
+}
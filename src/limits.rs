@@ -0,0 +1,211 @@
+use csv::Reader as CsvReader;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+use crate::clock::{Moment, Window};
+use crate::error::{Error, Result};
+
+/// Row of the credit limits file.
+#[derive(Debug, Deserialize)]
+struct CreditLimit {
+    client: u16,
+    limit: Decimal,
+}
+
+/// CreditLimits is how far below zero each client's available funds may go.
+#[derive(Debug, Default)]
+pub(crate) struct CreditLimits {
+    default: Decimal,
+    inner: HashMap<u16, Decimal>,
+}
+
+impl CreditLimits {
+    pub(crate) fn new(default: Decimal) -> Self {
+        Self {
+            default,
+            inner: HashMap::new(),
+        }
+    }
+
+    /// Loads per client limits, clients not in the file get the default limit.
+    pub(crate) fn from_path(file: &str, default: Decimal) -> Result<Self> {
+        let mut rdr = CsvReader::from_path(file).map_err(|_| Error::InvalidData)?;
+        let mut limits = Self::new(default);
+        for result in rdr.deserialize() {
+            let row: CreditLimit = result.map_err(|_| Error::InvalidData)?;
+            limits.set(row.client, row.limit)?;
+        }
+        Ok(limits)
+    }
+
+    pub(crate) fn set(&mut self, client: u16, limit: Decimal) -> Result<()> {
+        if limit < Decimal::ZERO {
+            return Err(Error::InvalidData);
+        }
+        self.inner.insert(client, limit);
+        Ok(())
+    }
+
+    pub(crate) fn limit(&self, client: u16) -> Decimal {
+        self.inner.get(&client).copied().unwrap_or(self.default)
+    }
+}
+
+/// Row of the velocity rules file.
+/// A rule without client or tier applies to every client.
+/// A rule looks back on either `window` records or `window_secs` seconds.
+#[derive(Debug, Clone, Deserialize)]
+struct VelocityRow {
+    #[serde(default)]
+    client: Option<u16>,
+    #[serde(default)]
+    tier: Option<String>,
+    #[serde(default)]
+    window: Option<u64>,
+    #[serde(default)]
+    window_secs: Option<u64>,
+    #[serde(default)]
+    max_count: Option<usize>,
+    #[serde(default)]
+    max_amount: Option<Decimal>,
+}
+
+#[derive(Debug, Clone)]
+struct VelocityRule {
+    client: Option<u16>,
+    tier: Option<String>,
+    window: Window,
+    max_count: Option<usize>,
+    max_amount: Option<Decimal>,
+}
+
+/// Row of the client tiers file.
+#[derive(Debug, Deserialize)]
+struct ClientTier {
+    client: u16,
+    tier: String,
+}
+
+/// Velocity limits how much and how often clients withdraw.
+#[derive(Debug, Default)]
+pub(crate) struct Velocity {
+    rules: Vec<VelocityRule>,
+    tiers: HashMap<u16, String>,
+    // Moment and amount of recent withdrawals per client and currency.
+    history: HashMap<(u16, String), VecDeque<(Moment, Decimal)>>,
+}
+
+impl Velocity {
+    pub(crate) fn from_paths(rules: &str, tiers: Option<&str>) -> Result<Self> {
+        let rules = CsvReader::from_path(rules).map_err(|_| Error::InvalidData)?;
+        let tiers = match tiers {
+            Some(file) => Some(CsvReader::from_path(file).map_err(|_| Error::InvalidData)?),
+            None => None,
+        };
+        Self::from_csv(rules, tiers)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_readers<R: io::Read>(rules: R, tiers: R) -> Result<Self> {
+        Self::from_csv(
+            CsvReader::from_reader(rules),
+            Some(CsvReader::from_reader(tiers)),
+        )
+    }
+
+    fn from_csv<R: io::Read>(mut rules: CsvReader<R>, tiers: Option<CsvReader<R>>) -> Result<Self> {
+        let mut velocity = Self::default();
+        for result in rules.deserialize() {
+            velocity.add_rule(result.map_err(|_| Error::InvalidData)?)?;
+        }
+        if let Some(mut rdr) = tiers {
+            for result in rdr.deserialize() {
+                let row: ClientTier = result.map_err(|_| Error::InvalidData)?;
+                velocity.tiers.insert(row.client, row.tier);
+            }
+        }
+        Ok(velocity)
+    }
+
+    fn add_rule(&mut self, row: VelocityRow) -> Result<()> {
+        let window = match Window::from_options(row.window, row.window_secs) {
+            Some(Window::Records(0)) | Some(Window::Seconds(0)) | None => {
+                return Err(Error::InvalidData)
+            }
+            Some(window) => window,
+        };
+        self.rules.push(VelocityRule {
+            client: row.client,
+            tier: row.tier,
+            window,
+            max_count: row.max_count,
+            max_amount: row.max_amount,
+        });
+        Ok(())
+    }
+
+    fn applies(&self, rule: &VelocityRule, client: u16) -> bool {
+        rule.client.is_none_or(|id| id == client)
+            && rule
+                .tier
+                .as_ref()
+                .is_none_or(|tier| self.tiers.get(&client) == Some(tier))
+    }
+
+    /// Checks that a withdrawal stays within every rule of the client.
+    /// Rules with a window in seconds only count withdrawals that have timestamps.
+    pub(crate) fn check(
+        &self,
+        client: u16,
+        currency: &str,
+        at: Moment,
+        amount: Decimal,
+    ) -> Result<()> {
+        let history = self.history.get(&(client, currency.to_string()));
+        for rule in self.rules.iter().filter(|rule| self.applies(rule, client)) {
+            let mut recent = history
+                .into_iter()
+                .flatten()
+                .filter(|(moment, _)| rule.window.contains(*moment, at) == Some(true));
+            let (count, total) = recent.try_fold((1, amount), |(count, total), (_, amount)| {
+                let total = total.checked_add(*amount).ok_or(Error::Overflow)?;
+                Ok::<_, Error>((count + 1, total))
+            })?;
+            if let Some(max_count) = rule.max_count.filter(|max| count > *max) {
+                return Err(Error::VelocityLimit(format!(
+                    "more than {} withdrawals in {}",
+                    max_count, rule.window
+                )));
+            }
+            if let Some(max_amount) = rule.max_amount.filter(|max| total > *max) {
+                return Err(Error::VelocityLimit(format!(
+                    "more than {} {} withdrawn in {}",
+                    max_amount, currency, rule.window
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a withdrawal that was applied.
+    pub(crate) fn record(&mut self, client: u16, currency: &str, at: Moment, amount: Decimal) {
+        let history = self
+            .history
+            .entry((client, currency.to_string()))
+            .or_default();
+        history.push_back((at, amount));
+        // Drop withdrawals that are outside the window of every rule.
+        while let Some((moment, _)) = history.front() {
+            let expired = self
+                .rules
+                .iter()
+                .all(|rule| rule.window.contains(*moment, at) == Some(false));
+            if !expired {
+                break;
+            }
+            history.pop_front();
+        }
+    }
+}
@@ -1,19 +1,35 @@
+mod access;
 mod account;
+mod audit;
+mod clock;
+mod compliance;
+mod config;
 mod error;
+mod fees;
+mod fx;
 mod io;
+mod ledger;
+mod limits;
 mod processor;
+mod reorder;
+mod risk;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod statement;
+mod store;
 
+use crate::config::Config;
 use crate::io::Reader;
 use std::env;
 
 fn main() {
     env_logger::init();
 
-    let args: Vec<String> = env::args().collect();
-    let file = args.get(1).expect("expected filename");
+    let config = Config::from_args(env::args().skip(1)).expect("invalid arguments");
+    let file = config.input.clone().expect("expected filename");
 
     let reader = Reader::from_path(file.as_str()).expect("failed to create reader");
-    let proc = processor::Processor::new_with(reader);
+    let proc = processor::Processor::new_with(reader).with_config(config);
     if let Err(e) = proc.start() {
         eprintln!("Error: {}", e);
     }
@@ -1,10 +1,35 @@
 use rust_decimal::Decimal;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::fs::{self, File};
+use std::path::Path;
 use std::str::FromStr;
 
-use crate::account::Accounts;
+use crate::access::Access;
+use crate::account::{self, Accounts, Bucket, CheckMode, Expiry, ExpiryPolicy, DEFAULT_CURRENCY};
+use crate::audit::Violation;
+use crate::clock::Moment;
+use crate::compliance::Compliance;
+use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::io::{Reader, Writer};
+use crate::fees::{Schedule, DEFAULT_HOUSE_ACCOUNT};
+use crate::fx::Rates;
+use crate::io::{self, Reader, Writer};
+use crate::ledger;
+use crate::limits::{CreditLimits, Velocity};
+use crate::reorder::Reorder;
+use crate::risk::{self, Engine};
+use crate::statement::Statement;
+use crate::store;
+
+// Files of the close of a run.
+const ACCOUNTS_FILE: &str = "accounts.csv";
+const STATEMENTS_DIR: &str = "statements";
+const ARCHIVE_FILE: &str = "archive.csv";
+const BALANCES_FILE: &str = "balances.csv";
+const CARRIED_FILE: &str = "transactions.csv";
+const LOCKS_FILE: &str = "locks.csv";
+const TX_IDS_FILE: &str = "tx_ids.csv";
+const FEES_FILE: &str = "fees.csv";
 
 // This deserializer is needed to make sure precision is up to 4 decimal places.
 fn deserialize_amount<'de, D>(amount: D) -> std::result::Result<Option<Decimal>, D::Error>
@@ -27,6 +52,22 @@ where
     Ok(Some(decimal))
 }
 
+// Currency codes are case insensitive, an empty column means the default currency.
+fn deserialize_currency<'de, D>(currency: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let buf = String::deserialize(currency)?;
+    let code = buf.trim().to_uppercase();
+    if code.is_empty() {
+        return Ok(None);
+    }
+    if !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(serde::de::Error::custom("invalid currency"));
+    }
+    Ok(Some(code))
+}
+
 // This deserializer the type since we can't use serde 'tag's with csv.
 fn deserialize_type<'de, D>(amount: D) -> std::result::Result<Type, D::Error>
 where
@@ -39,18 +80,34 @@ where
         "dispute" => Type::Dispute,
         "resolve" => Type::Resolve,
         "chargeback" => Type::Chargeback,
+        "transfer" => Type::Transfer,
+        "authorize" => Type::Authorize,
+        "capture" => Type::Capture,
+        "release" => Type::Release,
+        "representment" => Type::Representment,
+        "refund" => Type::Refund,
+        "reversal" => Type::Reversal,
+        "adjustment" => Type::Adjustment,
         _ => return Err(serde::de::Error::custom("invalid type")),
     };
     Ok(ty)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-enum Type {
+pub(crate) enum Type {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+    Authorize,
+    Capture,
+    Release,
+    Representment,
+    Refund,
+    Reversal,
+    Adjustment,
 }
 
 /// Record from csv.
@@ -62,6 +119,47 @@ pub(crate) struct Record {
     tx: u32,
     #[serde(deserialize_with = "deserialize_amount")]
     amount: Option<Decimal>,
+    // Only used by transfers, the client receiving the funds.
+    #[serde(default)]
+    destination: Option<u16>,
+    #[serde(default, deserialize_with = "deserialize_currency")]
+    currency: Option<String>,
+    // Seconds since the unix epoch.
+    #[serde(default)]
+    timestamp: Option<u64>,
+    // Only used by adjustments, the reason code, who made it and the part of the balance it changes.
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    operator: Option<String>,
+    #[serde(default)]
+    bucket: Option<Bucket>,
+}
+
+impl Record {
+    pub(crate) fn ty(&self) -> &Type {
+        &self.ty
+    }
+
+    pub(crate) fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub(crate) fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub(crate) fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    pub(crate) fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    pub(crate) fn currency(&self) -> &str {
+        self.currency.as_deref().unwrap_or(DEFAULT_CURRENCY)
+    }
 }
 
 /// Processor processes the transactions.
@@ -69,13 +167,29 @@ pub struct Processor {
     reader: Reader,
     writer: Writer,
     accounts: Accounts,
+    config: Config,
+    velocity: Velocity,
+    risk: Engine,
+    compliance: Option<Compliance>,
+    access: Access,
+    // Position of the record being processed.
+    position: u64,
+    violations: Vec<Violation>,
 }
 
 impl Processor {
     // We need this function for testing.
+    // With the sqlite feature, tests run against a SQLite store.
     #[cfg(test)]
     fn new() -> Self {
-        Self::new_with(Reader::from_path("/").unwrap())
+        #[allow(unused_mut)]
+        let mut processor = Self::new_with(Reader::from_path("/").unwrap());
+        #[cfg(feature = "sqlite")]
+        {
+            let store = crate::sqlite::SqliteStore::in_memory().unwrap();
+            processor.accounts = Accounts::with_store(Box::new(store)).unwrap();
+        }
+        processor
     }
 
     pub fn new_with(reader: Reader) -> Self {
@@ -83,46 +197,401 @@ impl Processor {
             reader,
             writer: Writer::new(),
             accounts: Accounts::new(),
+            config: Config::default(),
+            velocity: Velocity::default(),
+            risk: Engine::default(),
+            compliance: None,
+            access: Access::default(),
+            position: 0,
+            violations: Vec::new(),
         }
     }
 
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
     /// Start reading transactions using the Reader and writing results using the Writer.
     pub fn start(mut self) -> Result<()> {
+        self.configure()?;
+        let mut reorder = self.config.reorder_window.map(Reorder::new);
         for record in self.reader.read()? {
-            if let Err(e) = self.process(record) {
-                log::error!("{}", e);
+            let records = match &mut reorder {
+                Some(reorder) => match reorder.push(record) {
+                    Ok(records) => records,
+                    Err(e) => {
+                        log::error!("{}", e);
+                        continue;
+                    }
+                },
+                None => vec![record],
+            };
+            for record in records {
+                self.run(record)?;
+            }
+        }
+        for record in reorder.iter_mut().flat_map(Reorder::flush) {
+            self.run(record)?;
+        }
+        if self.config.check_invariants.is_some() {
+            self.check_invariants(None)?;
+        }
+
+        match (&self.config.statement, &self.config.base_currency) {
+            (Some(statement), _) => {
+                statement.write(&self.accounts.history(statement.client)?, std::io::stdout())?
+            }
+            (None, Some(base)) => {
+                let rates = match &self.config.fx_rates {
+                    Some(file) => Rates::from_path(file)?,
+                    None => Rates::default(),
+                };
+                self.writer.write_consolidated(
+                    &self.accounts.accounts()?,
+                    &rates,
+                    base,
+                    self.config.rounding,
+                )?
+            }
+            (None, None) => self.writer.write(&self.accounts.accounts()?)?,
+        }
+        if let Some(file) = &self.config.fees_out {
+            io::stream_csv(file, |f| self.accounts.fee_postings(f))?;
+        }
+        if let Some(file) = &self.config.flags_out {
+            io::write_csv(file, self.risk.flags())?;
+        }
+        if let Some(file) = &self.config.audit_out {
+            io::stream_csv(file, |f| self.accounts.audit(f))?;
+        }
+        if let Some(file) = &self.config.violations_out {
+            io::write_csv(file, &self.violations)?;
+        }
+        if let Some(file) = &self.config.transactions_out {
+            let statuses = &self.config.transaction_statuses;
+            io::stream_csv(file, |f| self.accounts.transactions(statuses, f))?;
+        }
+        if let Some(file) = &self.config.locked_out {
+            io::write_csv(file, self.accounts.locked_accounts()?)?;
+        }
+        if let Some(file) = &self.config.disputes_out {
+            io::stream_csv(file, |f| self.accounts.pending_disputes(f))?;
+        }
+        if let Some(file) = &self.config.journal_out {
+            io::stream_csv(file, |f| {
+                self.accounts
+                    .entries(&mut |entry| ledger::rows(entry).try_for_each(&mut *f))
+            })?;
+        }
+        if let (Some(file), Some(compliance)) = (&self.config.compliance_out, &self.compliance) {
+            io::write_csv(file, compliance.entries())?;
+        }
+        if let Some(dir) = self.config.close.clone() {
+            self.close(Path::new(&dir))?;
+        }
+        Ok(())
+    }
+
+    /// Closes the run into a directory: the accounts, a statement per client,
+    /// the archived transactions, and the balances, transactions, fees, locks and used tx ids
+    /// the next run starts from.
+    fn close(&mut self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir.join(STATEMENTS_DIR)).map_err(|_| Error::InvalidData)?;
+        let create = |path| File::create(path).map_err(|_| Error::InvalidData);
+
+        let mut writer = Writer::from_writer(create(dir.join(ACCOUNTS_FILE))?);
+        writer.set_uncleared(self.config.clearing_period.is_some());
+        let accounts = self.accounts.accounts()?;
+        writer.write(&accounts)?;
+        for account in &accounts {
+            let client = account.client();
+            let file = dir.join(STATEMENTS_DIR).join(format!("{}.csv", client));
+            let statement = Statement {
+                client,
+                ..Statement::default()
+            };
+            statement.write(&self.accounts.history(client)?, create(file)?)?;
+        }
+
+        io::stream_csv(path(&dir.join(ARCHIVE_FILE))?, |f| self.accounts.close(f))?;
+        let mut writer = Writer::from_writer(create(dir.join(BALANCES_FILE))?);
+        writer.set_uncleared(true);
+        writer.write(&self.accounts.accounts()?)?;
+        io::stream_csv(path(&dir.join(CARRIED_FILE))?, |f| self.accounts.carried(f))?;
+        io::stream_csv(path(&dir.join(FEES_FILE))?, |f| {
+            self.accounts.carried_fees(f)
+        })?;
+        io::write_csv(path(&dir.join(LOCKS_FILE))?, self.accounts.carried_locks()?)?;
+        io::stream_csv(path(&dir.join(TX_IDS_FILE))?, |f| self.accounts.archived(f))
+    }
+
+    /// Loads the files given in the config.
+    fn configure(&mut self) -> Result<()> {
+        if let Some(file) = &self.config.store {
+            self.accounts = Accounts::with_store(store::open(file)?)?;
+            // Positions go on from the last record the store was left at.
+            self.position = self.accounts.position();
+        }
+        if let Some(file) = &self.config.fee_schedule {
+            let house = self.config.house_account.unwrap_or(DEFAULT_HOUSE_ACCOUNT);
+            self.accounts.set_fees(Schedule::from_path(file)?, house);
+        }
+        let default_limit = self.config.default_credit_limit;
+        let limits = match &self.config.credit_limits {
+            Some(file) => CreditLimits::from_path(file, default_limit)?,
+            None => CreditLimits::new(default_limit),
+        };
+        self.accounts.set_credit_limits(limits);
+        if let Some(file) = &self.config.opening_balances {
+            for opening in account::opening_balances_from_path(file)? {
+                self.accounts.open(&opening)?;
+            }
+        }
+        if let Some(dir) = &self.config.carry_forward {
+            let dir = Path::new(dir);
+            for lock in io::read_csv(path(&dir.join(LOCKS_FILE))?)? {
+                self.accounts.carry_lock(&lock)?;
+            }
+            for opening in account::opening_balances_from_path(path(&dir.join(BALANCES_FILE))?)? {
+                self.accounts.open(&opening)?;
+            }
+            for archived in io::read_csv(path(&dir.join(TX_IDS_FILE))?)? {
+                self.accounts.carry_archived(&archived)?;
+            }
+            for carried in io::read_csv(path(&dir.join(CARRIED_FILE))?)? {
+                self.accounts.carry(&carried)?;
+            }
+            for posting in io::read_csv(path(&dir.join(FEES_FILE))?)? {
+                self.accounts.carry_fee(&posting)?;
+            }
+        }
+        if let Some(file) = &self.config.velocity_rules {
+            self.velocity = Velocity::from_paths(file, self.config.client_tiers.as_deref())?;
+        }
+        if let Some(file) = &self.config.risk_rules {
+            for rule in risk::rules_from_path(file)? {
+                self.risk.add_rule(rule);
+            }
+        }
+        let expiry = self.config.dispute_expiry.map(|window| Expiry {
+            window,
+            policy: self.config.expiry_policy.unwrap_or(ExpiryPolicy::Resolve),
+        });
+        self.accounts
+            .set_dispute_window(self.config.dispute_window, expiry);
+        self.accounts.set_clearing(self.config.clearing_period);
+        self.accounts
+            .set_unlock_on_representment(self.config.unlock_on_representment);
+        self.writer
+            .set_uncleared(self.config.clearing_period.is_some());
+        self.access = Access::from_paths(
+            self.config.blocklist.as_deref(),
+            self.config.allowlist.as_deref(),
+        )?;
+        if self.config.lock_blocked {
+            for client in self.access.blocked() {
+                self.accounts.lock(client, "blocked")?;
             }
         }
+        if self.config.compliance_out.is_some() {
+            self.compliance = Some(Compliance::from_config(&self.config));
+        }
+        Ok(())
+    }
+
+    /// Processes a record, logging the error if it fails.
+    /// In audit mode the invariants are checked after every record.
+    fn run(&mut self, record: Record) -> Result<()> {
+        let tx = record.tx();
+        if let Err(e) = self.process(record) {
+            log::error!("{}", e);
+        }
+        if let Some(CheckMode::Record) = self.config.check_invariants {
+            self.check_invariants(Some(tx))?;
+        }
+        Ok(())
+    }
 
-        self.writer.write(self.accounts.accounts())?;
+    /// Reports every invariant violation once,
+    /// or fails on the first one if processing aborts on violations.
+    fn check_invariants(&mut self, tx: Option<u32>) -> Result<()> {
+        let reported: Vec<String> = self
+            .violations
+            .iter()
+            .map(|violation| violation.invariant.clone())
+            .collect();
+        for (invariant, detail) in self.accounts.violations()? {
+            if reported.contains(&invariant) {
+                continue;
+            }
+            let at = match tx {
+                Some(tx) => format!("tx {} at position {}", tx, self.position),
+                None => "the end of the run".to_string(),
+            };
+            if self.config.abort_on_violation {
+                return Err(Error::InvariantViolation(format!(
+                    "{}: {} after {}",
+                    invariant, detail, at
+                )));
+            }
+            log::error!("invariant violated after {}: {}: {}", at, invariant, detail);
+            self.violations.push(Violation {
+                position: self.position,
+                tx,
+                invariant,
+                detail,
+            });
+        }
         Ok(())
     }
 
     /// Process a single record.
     fn process(&mut self, record: Record) -> Result<()> {
+        self.position += 1;
+        let at = Moment {
+            position: self.position,
+            timestamp: record.timestamp,
+        };
+        // Everything a record changes is stored at once. The disputes that expired by it
+        // are settled even if it's rejected, but a rejected record leaves nothing behind.
+        self.accounts.begin()?;
+        if let Err(e) = self.accounts.advance(at) {
+            self.accounts.rollback()?;
+            return Err(e);
+        }
+        self.accounts.begin()?;
+        let result = self.admit(&record);
+        match result {
+            Ok(()) => self.accounts.commit()?,
+            Err(_) => self.accounts.rollback()?,
+        }
+        self.accounts.commit()?;
+        result
+    }
+
+    // Checks a record against the access lists and risk rules, applying it if it passes.
+    fn admit(&mut self, record: &Record) -> Result<()> {
+        self.access.check(record.client)?;
+        if let Some(destination) = record.destination {
+            self.access.check(destination)?;
+        }
+        self.risk.check(record, self.position)?;
+        self.apply(record)?;
+        self.risk.record(record, self.position);
+        if let Some(compliance) = &mut self.compliance {
+            compliance.observe(record, self.position)?;
+        }
+        Ok(())
+    }
+
+    /// Applies a record to the accounts.
+    fn apply(&mut self, record: &Record) -> Result<()> {
+        let currency = record.currency();
         match record.ty {
             Type::Deposit => {
                 let amount = record.amount.ok_or(Error::InvalidData)?;
-                self.accounts.deposit(record.client, amount, record.tx)?
+                self.accounts
+                    .deposit(record.client, currency, amount, record.tx)?
             }
             Type::Withdrawal => {
                 let amount = record.amount.ok_or(Error::InvalidData)?;
-                self.accounts.withdraw(record.client, amount, record.tx)?
+                let at = Moment {
+                    position: self.position,
+                    timestamp: record.timestamp,
+                };
+                self.velocity.check(record.client, currency, at, amount)?;
+                self.accounts
+                    .withdraw(record.client, currency, amount, record.tx)?;
+                self.velocity.record(record.client, currency, at, amount);
+            }
+            // Authorizations count as withdrawals, as their captures pay out.
+            Type::Authorize => {
+                let amount = record.amount.ok_or(Error::InvalidData)?;
+                let at = Moment {
+                    position: self.position,
+                    timestamp: record.timestamp,
+                };
+                self.velocity.check(record.client, currency, at, amount)?;
+                self.accounts
+                    .authorize(record.client, currency, amount, record.tx)?;
+                self.velocity.record(record.client, currency, at, amount);
+            }
+            Type::Dispute => {
+                self.accounts
+                    .dispute(record.client, record.currency.as_deref(), record.tx)?
+            }
+            Type::Resolve => {
+                self.accounts
+                    .resolve(record.client, record.currency.as_deref(), record.tx)?
+            }
+            Type::Chargeback => {
+                self.accounts
+                    .chargeback(record.client, record.currency.as_deref(), record.tx)?
+            }
+            Type::Transfer => {
+                let amount = record.amount.ok_or(Error::InvalidData)?;
+                let destination = record.destination.ok_or(Error::InvalidData)?;
+                self.accounts
+                    .transfer(record.client, destination, currency, amount, record.tx)?
+            }
+            Type::Capture => self.accounts.capture(record.client, record.tx)?,
+            Type::Release => self.accounts.release(record.client, record.tx)?,
+            Type::Representment => {
+                self.accounts
+                    .represent(record.client, record.currency.as_deref(), record.tx)?
+            }
+            Type::Refund => self.accounts.refund(
+                record.client,
+                record.currency.as_deref(),
+                record.amount,
+                record.tx,
+            )?,
+            Type::Reversal => {
+                self.accounts
+                    .reverse(record.client, record.currency.as_deref(), record.tx)?
+            }
+            // Adjustments aren't subject to velocity limits.
+            Type::Adjustment => {
+                let amount = record.amount.ok_or(Error::InvalidData)?;
+                self.accounts.adjust(
+                    record.client,
+                    currency,
+                    record.bucket.unwrap_or_default(),
+                    amount,
+                    record.tx,
+                    record.reason.as_deref().unwrap_or_default().trim(),
+                    record.operator.as_deref().unwrap_or_default().trim(),
+                )?
             }
-            Type::Dispute => self.accounts.dispute(record.client, record.tx)?,
-            Type::Resolve => self.accounts.resolve(record.client, record.tx)?,
-            Type::Chargeback => self.accounts.chargeback(record.client, record.tx)?,
         }
         Ok(())
     }
 }
 
+fn path(path: &Path) -> Result<&str> {
+    path.to_str().ok_or(Error::InvalidData)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::error::Error;
+    use crate::access::Access;
+    use crate::account::{self, CheckMode, Expiry, ExpiryPolicy, Status};
+    use crate::clock::Window;
+    use crate::compliance::Compliance;
+    use crate::error::{Error, Result};
+    use crate::fees::{FeeType, Schedule};
+    use crate::fx::{Rates, Rounding};
+    use crate::io::Writer;
+    use crate::ledger::Book;
+    use crate::limits::{CreditLimits, Velocity};
     use crate::processor::{Processor, Record};
+    use crate::reorder::Reorder;
+    use crate::risk;
+    use crate::statement::{Format, Statement};
+    use crate::store::Visit;
     use csv::Reader;
-    use rust_decimal::Decimal;
+    use rust_decimal::{Decimal, RoundingStrategy};
 
     // Creates Records from strings.
     macro_rules! records {
@@ -153,6 +622,17 @@ mod tests {
         }};
     }
 
+    // Collects the rows a scan visits.
+    fn rows<T>(scan: impl FnOnce(Visit<T>) -> Result<()>) -> Vec<T> {
+        let mut rows = Vec::new();
+        scan(&mut |row| {
+            rows.push(row);
+            Ok(())
+        })
+        .unwrap();
+        rows
+    }
+
     #[test]
     fn deposit_success() {
         let records = records!(
@@ -382,7 +862,7 @@ mod tests {
         // Invalid precision.
         let mut data = String::from("type,client,tx,amount\n");
         data.push_str("deposit,1,61,4.32111");
-        data.push_str("\n");
+        data.push('\n');
 
         let mut rdr = Reader::from_reader(data.as_bytes());
         let err_msg = rdr.deserialize::<Record>().next().unwrap();
@@ -425,4 +905,1441 @@ mod tests {
         );
         assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(500));
     }
+
+    // Creates Records from strings using the given header.
+    macro_rules! records_with {
+        ($header:expr, $($str:tt), *) => {{
+            let mut data = String::from($header);
+            data.push('\n');
+            $(
+                data.push_str($str);
+                data.push('\n');
+            )*
+            let mut records = Vec::new();
+            let mut rdr = Reader::from_reader(data.as_bytes());
+            for result in rdr.deserialize() {
+                let record: Record = result.unwrap();
+                records.push(record);
+            }
+            records
+        }}
+    }
+
+    #[test]
+    fn transfer() {
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,1,1,100,",
+            "transfer,1,2,60,2",
+            "deposit,2,3,10,"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(processor.accounts.account(1).unwrap().available(), dec!(40));
+        assert_eq!(processor.accounts.account(2).unwrap().available(), dec!(70));
+
+        // Neither account changes if the source cannot cover the transfer.
+        let records = records_with!("type,client,tx,amount,destination", "transfer,1,4,50,2");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::InsufficientFunds)
+        );
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(40));
+        assert_eq!(processor.accounts.account(2).unwrap().total(), dec!(70));
+
+        // Transfers can't pull funds from the destination.
+        let records = records_with!("type,client,tx,amount,destination", "transfer,1,4,-20,2");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::InvalidData)
+        );
+        assert_eq!(processor.accounts.account(2).unwrap().total(), dec!(70));
+
+        // Charging back a transfer returns the funds to the sender and locks the receiver.
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "dispute,1,2,,",
+            "dispute,2,2,,",
+            "chargeback,2,2,,",
+            "transfer,1,5,10,2"
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(100));
+        assert_eq!(processor.accounts.account(2).unwrap().total(), dec!(10));
+        assert!(processor.accounts.account(2).unwrap().frozen());
+    }
+
+    #[test]
+    fn currencies() {
+        let records = records_with!(
+            "type,client,tx,amount,currency",
+            "deposit,1,1,100,",
+            "deposit,1,2,50,eur",
+            "withdrawal,1,3,20,EUR"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(100));
+        assert_eq!(account.available_in("EUR"), dec!(30));
+        assert_eq!(account.total_in("EUR"), dec!(30));
+
+        // Balances are kept apart, so funds in one currency don't cover another.
+        let records = records_with!("type,client,tx,amount,currency", "withdrawal,1,4,40,EUR");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::InsufficientFunds)
+        );
+
+        // Resolving in a currency other than the transaction's is rejected.
+        let records = records_with!("type,client,tx,amount,currency", "resolve,1,2,,USD");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::CurrencyMismatch)
+        );
+    }
+
+    #[test]
+    fn consolidated() {
+        let records = records_with!(
+            "type,client,tx,amount,currency",
+            "deposit,1,1,100,",
+            "deposit,1,2,10,EUR",
+            "deposit,1,3,1000,JPY"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let rates =
+            Rates::from_reader("from,to,rate\nEUR,USD,1.1\nUSD,JPY,150\n".as_bytes()).unwrap();
+        let rounding = Rounding {
+            dp: 2,
+            strategy: RoundingStrategy::MidpointNearestEven,
+        };
+
+        let mut writer = Writer::from_writer(Vec::new());
+        writer
+            .write_consolidated(
+                &processor.accounts.accounts().unwrap(),
+                &rates,
+                "USD",
+                rounding,
+            )
+            .unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "client,currency,available,held,total,locked\n1,USD,117.67,0.00,117.67,false\n"
+        );
+
+        // Balances in a currency without a rate can't be consolidated.
+        let mut writer = Writer::from_writer(Vec::new());
+        assert_eq!(
+            writer.write_consolidated(
+                &processor.accounts.accounts().unwrap(),
+                &rates,
+                "GBP",
+                rounding
+            ),
+            Err(Error::MissingRate("EUR".to_string(), "GBP".to_string()))
+        );
+    }
+
+    #[test]
+    fn fees() {
+        let schedule = Schedule::from_reader(
+            "type,method,value,min_amount\n\
+             withdrawal,flat,1,\n\
+             withdrawal,percentage,1,100\n\
+             dispute,flat,5,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut processor = Processor::new();
+        processor.accounts.set_fees(schedule, 0);
+
+        let records = records!(
+            "deposit,1,1,500",
+            "deposit,1,5,20",
+            "withdrawal,1,2,50",
+            "withdrawal,1,3,200",
+            "dispute,1,5,"
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        // Flat fee below the tier, 1% above it, and the dispute fee.
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(262));
+        assert_eq!(processor.accounts.account(0).unwrap().total(), dec!(8));
+
+        // Resolving the dispute gives the dispute fee back, even once the house paid it out.
+        let records = records!("withdrawal,0,6,7", "resolve,1,5,");
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(267)
+        );
+        assert_eq!(processor.accounts.account(0).unwrap().total(), dec!(-4));
+
+        let postings = rows(|f| processor.accounts.fee_postings(f));
+        assert_eq!(postings.len(), 4);
+        assert!(postings[2].reversed);
+        assert_eq!(postings[2].ty, FeeType::Dispute);
+
+        // The withdrawal fails if the fee can't be paid.
+        let records = records!("withdrawal,1,4,267");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::InsufficientFunds)
+        );
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(267)
+        );
+    }
+
+    #[test]
+    fn house_fees() {
+        let schedule = Schedule::from_reader(
+            "type,method,value,min_amount\n\
+             withdrawal,flat,1,\n\
+             transfer,flat,1,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut processor = Processor::new();
+        processor.accounts.set_fees(schedule, 7);
+        processor.config.check_invariants = Some(CheckMode::Record);
+
+        // The house account keeps fees it receives by transfer and fees it pays itself.
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,1,1,100,",
+            "transfer,1,2,50,7",
+            "withdrawal,7,3,10,"
+        );
+        for record in records {
+            processor.run(record).unwrap();
+        }
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(49));
+        assert_eq!(processor.accounts.account(7).unwrap().total(), dec!(41));
+        assert!(processor.violations.is_empty());
+    }
+
+    #[test]
+    fn credit_limits() {
+        let mut limits = CreditLimits::new(dec!(10));
+        limits.set(2, dec!(100)).unwrap();
+        let mut processor = Processor::new();
+        processor.accounts.set_credit_limits(limits);
+
+        let records = records!(
+            "deposit,1,1,50",
+            "deposit,2,2,50",
+            "withdrawal,1,3,55",
+            "withdrawal,2,4,120"
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(processor.accounts.account(1).unwrap().available(), dec!(-5));
+        assert_eq!(
+            processor.accounts.account(2).unwrap().available(),
+            dec!(-70)
+        );
+
+        // Going past the limit is rejected.
+        let records = records!("withdrawal,1,5,6");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::InsufficientFunds)
+        );
+
+        // Charging back spent funds leaves a negative balance.
+        let records = records!("dispute,2,2,", "chargeback,2,2,");
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let account = processor.accounts.account(2).unwrap();
+        assert_eq!(account.available(), dec!(-120));
+        assert_eq!(account.total(), dec!(-120));
+        assert!(account.frozen());
+    }
+
+    #[test]
+    fn foreign_disputes() {
+        let records = records!("deposit,1,1,100", "dispute,2,1,", "chargeback,2,1,");
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        // Clients can only dispute their own transactions.
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(100)
+        );
+        let account = processor.accounts.account(2).unwrap();
+        assert_eq!(account.total(), dec!(0));
+        assert!(!account.frozen());
+        assert_eq!(processor.accounts.status_history(1), vec![Status::Open]);
+    }
+
+    #[test]
+    fn velocity() {
+        let velocity = Velocity::from_readers(
+            "client,tier,window,max_count,max_amount\n\
+             ,,3,2,\n\
+             ,gold,5,,100\n"
+                .as_bytes(),
+            "client,tier\n2,gold\n".as_bytes(),
+        )
+        .unwrap();
+        let mut processor = Processor::new();
+        processor.velocity = velocity;
+
+        let records = records!(
+            "deposit,1,1,1000",
+            "deposit,2,2,1000",
+            "withdrawal,1,3,10",
+            "withdrawal,1,4,10",
+            "withdrawal,1,5,10"
+        );
+        let mut results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        assert_eq!(
+            results.pop().unwrap(),
+            Err(Error::VelocityLimit(
+                "more than 2 withdrawals in 3 records".to_string()
+            ))
+        );
+        assert!(results.into_iter().all(|result| result.is_ok()));
+
+        // Once the earlier withdrawals fall out of the window the client can withdraw again.
+        let records = records!("withdrawal,1,6,10");
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(970)
+        );
+
+        // Only the gold tier is limited by amount, which authorizations count towards.
+        let records = records!("withdrawal,2,7,60", "authorize,2,8,60");
+        let mut records_iter = records.into_iter();
+        processor.process(records_iter.next().unwrap()).unwrap();
+        assert_eq!(
+            processor.process(records_iter.next().unwrap()),
+            Err(Error::VelocityLimit(
+                "more than 100 USD withdrawn in 5 records".to_string()
+            ))
+        );
+        assert_eq!(
+            processor.accounts.account(2).unwrap().available(),
+            dec!(940)
+        );
+
+        // Sums of withdrawals that overflow fail instead of panicking.
+        let records = records!(
+            "deposit,3,9,50000000000000000000000000000",
+            "withdrawal,3,10,50000000000000000000000000000",
+            "withdrawal,3,11,50000000000000000000000000000"
+        );
+        let mut results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        assert_eq!(results.pop().unwrap(), Err(Error::Overflow));
+        assert!(results.into_iter().all(|result| result.is_ok()));
+    }
+
+    #[test]
+    fn risk_rules() {
+        let rules = risk::rules_from_reader(
+            "rule,action,limit,window\n\
+             deposit-withdrawal,reject,1000,2\n\
+             repeated-disputes,flag,1,\n\
+             dispute-ratio,reject,0.5,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut processor = Processor::new();
+        for rule in rules {
+            processor.risk.add_rule(rule);
+        }
+
+        // Withdrawing right after a large deposit is rejected.
+        let records = records!("deposit,1,1,5000", "withdrawal,1,2,4000");
+        let mut records_iter = records.into_iter();
+        processor.process(records_iter.next().unwrap()).unwrap();
+        assert!(matches!(
+            processor.process(records_iter.next().unwrap()),
+            Err(Error::Rejected(rule, _)) if rule == "deposit-withdrawal"
+        ));
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(5000)
+        );
+
+        // The second dispute is flagged, the third breaks the dispute ratio
+        // and isn't reported as flagged as it's rejected.
+        let records = records!(
+            "deposit,1,3,10",
+            "deposit,1,4,10",
+            "deposit,1,5,10",
+            "dispute,1,3,",
+            "dispute,1,4,",
+            "dispute,1,5,"
+        );
+        let results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        assert!(results[..5].iter().all(|result| result.is_ok()));
+        assert!(matches!(
+            &results[5],
+            Err(Error::Rejected(rule, _)) if rule == "dispute-ratio"
+        ));
+        let flags = processor.risk.flags();
+        assert_eq!(flags.len(), 1);
+        assert_eq!(flags[0].tx, 4);
+        assert_eq!(flags[0].rule, "repeated-disputes");
+    }
+
+    #[test]
+    fn compliance() {
+        let mut compliance = Compliance::default();
+        compliance.set_threshold(dec!(1000));
+        compliance.set_structuring(dec!(1, 1), 3, 5);
+        let mut processor = Processor::new();
+        processor.compliance = Some(compliance);
+
+        let records = records!(
+            "deposit,1,1,1500",
+            "withdrawal,1,2,1000",
+            "deposit,2,3,950",
+            "deposit,2,4,990",
+            "deposit,3,5,950",
+            "deposit,2,6,100",
+            "deposit,2,7,999.99",
+            "deposit,3,8,950"
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let entries = processor.compliance.as_ref().unwrap().entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[0].tx, entries[1].tx), (1, 2));
+        assert_eq!(entries[2].client, 2);
+        assert_eq!(entries[2].amount, dec!(293999, 2));
+        assert!(entries[2].reason.contains("3 4 7"));
+    }
+
+    #[test]
+    fn access() {
+        let mut access = Access::default();
+        access.block(2);
+        let mut processor = Processor::new();
+        processor.access = access;
+
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,1,1,100,",
+            "deposit,2,2,100,",
+            "transfer,1,3,50,2"
+        );
+        let results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Err(Error::BlockedClient(2)),
+                Err(Error::BlockedClient(2))
+            ]
+        );
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(100)
+        );
+        assert_eq!(processor.accounts.account(2).unwrap().available(), dec!(0));
+
+        // With an allowlist every other client is rejected.
+        let mut access = Access::default();
+        access.allow(1);
+        processor.access = access;
+        let records = records!("deposit,3,4,100");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::ClientNotAllowed(3))
+        );
+    }
+
+    #[test]
+    fn reorder() {
+        let records = records_with!(
+            "type,client,tx,amount,timestamp",
+            "deposit,1,1,100,10",
+            "withdrawal,1,3,150,14",
+            "deposit,1,2,100,12",
+            "deposit,1,4,100,20",
+            "deposit,1,5,100,11"
+        );
+        let mut reorder = Reorder::new(5);
+        let mut processor = Processor::new();
+        let mut results = Vec::new();
+        let mut released = Vec::new();
+        for record in records {
+            match reorder.push(record) {
+                Ok(records) => released.extend(records),
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        released.extend(reorder.flush());
+        for record in released {
+            results.push(processor.process(record));
+        }
+
+        // The withdrawal is applied after the deposit that arrived after it,
+        // and the deposit more than 5 seconds behind the latest record is rejected.
+        assert_eq!(
+            results,
+            vec![Err(Error::LateRecord(5)), Ok(()), Ok(()), Ok(()), Ok(())]
+        );
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(150)
+        );
+    }
+
+    #[test]
+    fn velocity_seconds() {
+        let velocity = Velocity::from_readers(
+            "client,tier,window,window_secs,max_count,max_amount\n,,,60,,100\n".as_bytes(),
+            "client,tier\n".as_bytes(),
+        )
+        .unwrap();
+        let mut processor = Processor::new();
+        processor.velocity = velocity;
+
+        let records = records_with!(
+            "type,client,tx,amount,timestamp",
+            "deposit,1,1,1000,0",
+            "withdrawal,1,2,60,0",
+            "withdrawal,1,3,60,59",
+            "withdrawal,1,4,60,60"
+        );
+        let results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        assert_eq!(
+            results[2],
+            Err(Error::VelocityLimit(
+                "more than 100 USD withdrawn in 60 seconds".to_string()
+            ))
+        );
+        assert_eq!(results[3], Ok(()));
+    }
+
+    #[test]
+    fn dispute_windows() {
+        let mut processor = Processor::new();
+        processor.accounts.set_dispute_window(
+            Some(Window::Records(3)),
+            Some(Expiry {
+                window: Window::Seconds(100),
+                policy: ExpiryPolicy::Chargeback,
+            }),
+        );
+
+        let records = records_with!(
+            "type,client,tx,amount,timestamp",
+            "deposit,1,1,100,0",
+            "deposit,1,2,50,10",
+            "deposit,2,3,10,20",
+            "dispute,1,2,,30",
+            "dispute,1,1,,40"
+        );
+        let results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        assert_eq!(results[3], Ok(()));
+        assert_eq!(results[4], Err(Error::DisputeWindowClosed(1)));
+
+        // The pending dispute is charged back once it expires.
+        let records = records_with!(
+            "type,client,tx,amount,timestamp",
+            "deposit,2,4,10,129",
+            "deposit,2,5,10,130"
+        );
+        let mut records_iter = records.into_iter();
+        processor.process(records_iter.next().unwrap()).unwrap();
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(150));
+        processor.process(records_iter.next().unwrap()).unwrap();
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.total(), dec!(100));
+        assert!(account.frozen());
+
+        let audit = rows(|f| processor.accounts.audit(f));
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].tx, 2);
+        assert_eq!(audit[0].action, "auto-chargeback");
+        assert_eq!(audit[0].position, 7);
+    }
+
+    #[test]
+    fn expiry_on_locked_accounts() {
+        let mut processor = Processor::new();
+        processor.accounts.set_dispute_window(
+            None,
+            Some(Expiry {
+                window: Window::Records(3),
+                policy: ExpiryPolicy::Resolve,
+            }),
+        );
+        processor.accounts.set_unlock_on_representment(true);
+        let records = records!(
+            "deposit,1,1,100",
+            "deposit,1,2,50",
+            "dispute,1,1,",
+            "dispute,1,2,",
+            "chargeback,1,2,",
+            "deposit,2,3,10"
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        // The expired dispute stays pending while the account is locked.
+        assert_eq!(
+            processor.accounts.status_history(1),
+            vec![Status::Open, Status::Pending]
+        );
+        let disputes = rows(|f| processor.accounts.pending_disputes(f));
+        let queue: Vec<(u32, u16)> = disputes.iter().map(|row| (row.tx, row.client)).collect();
+        assert_eq!(queue, vec![(1, 1)]);
+
+        // It's resolved once the account is unlocked.
+        let records = records!("representment,1,2,", "deposit,2,4,10");
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(
+            processor.accounts.status_history(1),
+            vec![Status::Open, Status::Pending, Status::Resolved]
+        );
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(150));
+        assert_eq!(account.total(), dec!(150));
+        assert!(rows(|f| processor.accounts.pending_disputes(f)).is_empty());
+    }
+
+    #[test]
+    fn failed_expiry() {
+        let mut processor = Processor::new();
+        processor.accounts.set_fees(
+            Schedule::from_reader("type,method,value\nchargeback,percentage,200\n".as_bytes())
+                .unwrap(),
+            0,
+        );
+        processor.accounts.set_dispute_window(
+            None,
+            Some(Expiry {
+                window: Window::Records(2),
+                policy: ExpiryPolicy::Chargeback,
+            }),
+        );
+        // The chargeback fee overflows, so the dispute can't be charged back when it expires.
+        let records = records!(
+            "deposit,1,1,50000000000000000000000000000",
+            "dispute,1,1,",
+            "deposit,2,2,10",
+            "deposit,3,3,10",
+            "deposit,4,4,10"
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let audit = rows(|f| processor.accounts.audit(f));
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].action, "auto-chargeback-failed");
+        assert_eq!(audit[0].position, 4);
+        assert_eq!(processor.accounts.account(4).unwrap().total(), dec!(10));
+
+        // It's left pending to be settled by hand.
+        processor
+            .process(records!("resolve,1,1,").remove(0))
+            .unwrap();
+        assert_eq!(
+            processor.accounts.status_history(1),
+            vec![Status::Open, Status::Pending, Status::Resolved]
+        );
+    }
+
+    #[test]
+    fn clearing() {
+        let mut processor = Processor::new();
+        processor.accounts.set_clearing(Some(Window::Records(3)));
+
+        let records = records!("deposit,1,1,100", "deposit,1,2,50", "withdrawal,1,3,10");
+        let results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        assert_eq!(results[2], Err(Error::InsufficientFunds));
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(0));
+        assert_eq!(account.total(), dec!(150));
+
+        // The first deposit clears, the second is disputed before clearing.
+        let records = records!("dispute,1,2,", "withdrawal,1,4,10");
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(90));
+        assert_eq!(account.total(), dec!(140));
+
+        // Resolving after the clearing period makes the funds available.
+        let records = records!("resolve,1,2,");
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(
+            processor.accounts.account(1).unwrap().available(),
+            dec!(140)
+        );
+
+        let mut writer = Writer::from_writer(Vec::new());
+        writer.set_uncleared(true);
+        writer
+            .write(&processor.accounts.accounts().unwrap())
+            .unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,uncleared,total,locked\n1,140,0,0,140,false\n"
+        );
+    }
+
+    #[test]
+    fn authorizations() {
+        let records = records!(
+            "deposit,1,1,100",
+            "authorize,1,2,30",
+            "authorize,1,3,50",
+            "capture,1,2,",
+            "release,1,3,",
+            "capture,1,3,"
+        );
+        let mut processor = Processor::new();
+        let mut records_iter = records.into_iter();
+        for record in records_iter.by_ref().take(3) {
+            processor.process(record).unwrap();
+        }
+        // Authorized funds are held and still count towards the total.
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(20));
+        assert_eq!(account.total(), dec!(100));
+
+        // Released funds can't be captured afterwards.
+        for record in records_iter {
+            processor.process(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(70));
+        assert_eq!(account.total(), dec!(70));
+
+        // Authorizations are limited by the available funds.
+        let records = records!("authorize,1,4,80");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::InsufficientFunds)
+        );
+
+        // And must be for a positive amount.
+        let records = records!("authorize,1,5,-10");
+        assert_eq!(
+            processor.process(records.into_iter().next().unwrap()),
+            Err(Error::InvalidData)
+        );
+        assert_eq!(processor.accounts.account(1).unwrap().available(), dec!(70));
+    }
+
+    #[test]
+    fn representment() {
+        let records = records!(
+            "deposit,1,1,100",
+            "deposit,1,2,50",
+            "dispute,1,2,",
+            "chargeback,1,2,",
+            "representment,1,2,"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        // The funds are back but the account stays locked.
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(150));
+        assert_eq!(account.total(), dec!(150));
+        assert!(account.frozen());
+        assert_eq!(
+            processor.accounts.status_history(2),
+            vec![
+                Status::Open,
+                Status::Pending,
+                Status::Chargeback,
+                Status::Represented
+            ]
+        );
+
+        // A transaction is represented at most once, and can unlock the account.
+        let mut processor = Processor::new();
+        processor.accounts.set_unlock_on_representment(true);
+        let records = records!(
+            "deposit,1,1,100",
+            "dispute,1,1,",
+            "chargeback,1,1,",
+            "representment,1,1,",
+            "representment,1,1,",
+            "deposit,1,2,10"
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(110));
+        assert!(!account.frozen());
+        assert_eq!(rows(|f| processor.accounts.audit(f)).len(), 1);
+    }
+
+    #[test]
+    fn refunds() {
+        let records = records!(
+            "deposit,1,1,100",
+            "withdrawal,1,2,30",
+            "refund,1,1,20",
+            "refund,1,2,",
+            "refund,1,1,"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        // Deposit refunded in two parts, withdrawal refunded in full.
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(0));
+        assert_eq!(account.total(), dec!(0));
+        assert!(!account.frozen());
+
+        let records = records!("deposit,1,3,50", "dispute,1,3,", "deposit,2,4,10");
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        // Nothing is left to refund, disputed deposits and other clients' deposits aren't refunded.
+        for record in records!(
+            "refund,1,1,1",
+            "refund,1,3,",
+            "refund,1,4,",
+            "refund,2,4,11"
+        ) {
+            assert!(matches!(
+                processor.process(record),
+                Err(Error::InvalidRefund(_, _))
+            ));
+        }
+        // Refunded transactions can't be disputed.
+        processor
+            .process(records!("dispute,1,1,").remove(0))
+            .unwrap();
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(50));
+    }
+
+    #[test]
+    fn reversals() {
+        let records = records!(
+            "deposit,1,1,100",
+            "deposit,1,2,40",
+            "reversal,1,2,",
+            "deposit,2,3,10",
+            "withdrawal,2,4,10"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(100));
+        assert_eq!(account.total(), dec!(100));
+        assert!(!account.frozen());
+        assert_eq!(
+            processor.accounts.status_history(2),
+            vec![Status::Open, Status::Reversed]
+        );
+
+        // Only open deposits of the client are reversed, and only with the funds to cover them.
+        for record in records!("reversal,1,2,", "reversal,2,4,", "reversal,2,1,") {
+            assert!(matches!(
+                processor.process(record),
+                Err(Error::InvalidReversal(_, _))
+            ));
+        }
+        let record = records!("reversal,2,3,").remove(0);
+        assert_eq!(processor.process(record), Err(Error::InsufficientFunds));
+    }
+
+    #[test]
+    fn adjustments() {
+        let records = records_with!(
+            "type,client,tx,amount,reason,operator,bucket",
+            "deposit,1,1,100,,,",
+            "adjustment,1,2,25,DUP-FIX,ops-7,",
+            "adjustment,1,3,-10,DUP-FIX,ops-7,available",
+            "adjustment,1,4,5,HOLD,ops-2,held",
+            "dispute,1,2,,,,"
+        );
+        let mut processor = Processor::new();
+        processor.velocity = Velocity::from_readers(
+            "window,max_count\n1,1\n".as_bytes(),
+            "client,tier\n".as_bytes(),
+        )
+        .unwrap();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(115));
+        assert_eq!(account.total(), dec!(120));
+        let audit = rows(|f| processor.accounts.audit(f));
+        assert_eq!(audit.len(), 3);
+        assert_eq!(audit[1].amount, dec!(-10));
+        assert_eq!(audit[1].reason, "DUP-FIX");
+        assert_eq!(audit[1].operator, "ops-7");
+
+        // Reason and operator are required, and held funds can't go negative.
+        let records = records_with!(
+            "type,client,tx,amount,reason,operator,bucket",
+            "adjustment,1,5,10,,ops-7,",
+            "adjustment,1,6,10,FIX,,",
+            "adjustment,1,7,0,FIX,ops-7,"
+        );
+        for record in records {
+            assert!(matches!(
+                processor.process(record),
+                Err(Error::InvalidAdjustment(_, _))
+            ));
+        }
+        let record = records_with!(
+            "type,client,tx,amount,reason,operator,bucket",
+            "adjustment,1,8,-6,FIX,ops-7,held"
+        )
+        .remove(0);
+        assert_eq!(processor.process(record), Err(Error::InsufficientFunds));
+    }
+
+    #[test]
+    fn ledger() {
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,1,1,100,",
+            "deposit,2,2,50,",
+            "transfer,1,3,30,2",
+            "withdrawal,2,4,20,",
+            "dispute,1,1,,",
+            "chargeback,1,1,,",
+            "authorize,2,5,10,",
+            "capture,2,5,,",
+            "dispute,2,3,,",
+            "chargeback,2,3,,"
+        );
+        let mut processor = Processor::new();
+        processor.accounts.set_fees(
+            Schedule::from_reader("type,method,value\nwithdrawal,flat,1\n".as_bytes()).unwrap(),
+            0,
+        );
+        for record in records {
+            processor.process(record).unwrap();
+        }
+
+        // Balances of the accounts are those of their books.
+        let ledger = &processor.accounts;
+        let mut clients = Decimal::ZERO;
+        for account in &processor.accounts.accounts().unwrap() {
+            for (currency, balance) in account.balances() {
+                let client = account.client();
+                assert_eq!(
+                    ledger.balance(Book::Available(client), currency),
+                    balance.available()
+                );
+                assert_eq!(ledger.balance(Book::Held(client), currency), balance.held());
+                clients += balance.total();
+            }
+        }
+        // What clients hold came in from outside.
+        let system: Decimal = [
+            Book::Funding,
+            Book::Payouts,
+            Book::Transfers,
+            Book::Fees,
+            Book::ChargebackLoss,
+        ]
+        .into_iter()
+        .map(|book| ledger.balance(book, "USD"))
+        .sum();
+        assert_eq!(clients, -system);
+        assert_eq!(ledger.balance(Book::Funding, "USD"), dec!(-150));
+        assert_eq!(ledger.balance(Book::Payouts, "USD"), dec!(30));
+        // The charged back transfer went back to the sender.
+        assert_eq!(ledger.balance(Book::ChargebackLoss, "USD"), dec!(100));
+        assert_eq!(ledger.balance(Book::Transfers, "USD"), dec!(0));
+    }
+
+    #[test]
+    fn ledger_overflow() {
+        let records = records!(
+            "deposit,1,1,50000000000000000000000000000",
+            "withdrawal,1,2,50000000000000000000000000000",
+            "deposit,1,3,50000000000000000000000000000"
+        );
+        let mut processor = Processor::new();
+        let results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        // The funding book overflows, so the deposit is rejected without crediting the client.
+        assert_eq!(results[2], Err(Error::Overflow));
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(0));
+        assert_eq!(rows(|f| processor.accounts.transactions(&[], f)).len(), 2);
+        processor.config.check_invariants = Some(CheckMode::End);
+        processor.check_invariants(None).unwrap();
+        assert!(processor.violations.is_empty());
+    }
+
+    #[test]
+    fn rejected_records() {
+        let schedule = Schedule::from_reader("type,method,value\ntransfer,flat,1\n".as_bytes());
+        let mut processor = Processor::new();
+        processor.accounts.set_fees(schedule.unwrap(), 0);
+        processor
+            .accounts
+            .set_credit_limits(CreditLimits::new(dec!(10)));
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,2,1,79228162514264337593543950335,",
+            "transfer,1,2,5,2",
+            "withdrawal,3,3,50,"
+        );
+        let results: Vec<_> = records
+            .into_iter()
+            .map(|record| processor.process(record))
+            .collect();
+        // The fee is charged before the transfer overflows its destination, and goes with it.
+        assert_eq!(results[1], Err(Error::Overflow));
+        assert!(rows(|f| processor.accounts.fee_postings(f)).is_empty());
+        // Rejected records leave no accounts behind either.
+        assert_eq!(results[2], Err(Error::InsufficientFunds));
+        let clients: Vec<u16> = processor
+            .accounts
+            .accounts()
+            .unwrap()
+            .iter()
+            .map(|account| account.client())
+            .collect();
+        assert_eq!(clients, vec![2]);
+        assert_eq!(rows(|f| processor.accounts.entries(f)).len(), 1);
+        assert!(processor.accounts.history(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invariants() {
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,1,1,100,",
+            "deposit,2,2,50,",
+            "transfer,1,3,30,2",
+            "withdrawal,2,4,20,",
+            "authorize,2,5,10,",
+            "dispute,1,1,,",
+            "dispute,2,3,,",
+            "chargeback,2,3,,",
+            "refund,2,2,5,",
+            "deposit,1,6,10,",
+            "reversal,1,6,,"
+        );
+        let mut processor = Processor::new();
+        processor.config.check_invariants = Some(CheckMode::Record);
+        for record in records {
+            processor.run(record).unwrap();
+        }
+        assert!(processor.violations.is_empty());
+
+        // Funds that appear out of nowhere are reported once, with the record after which they did.
+        processor.accounts.skew(1, dec!(5));
+        for record in records!("deposit,1,7,1", "deposit,1,8,1") {
+            processor.run(record).unwrap();
+        }
+        assert_eq!(processor.violations.len(), 1);
+        assert_eq!(processor.violations[0].tx, Some(7));
+        assert_eq!(processor.violations[0].invariant, "client 1 USD total");
+
+        processor.config.abort_on_violation = true;
+        processor.violations.clear();
+        let record = records!("deposit,1,9,1").remove(0);
+        assert!(matches!(
+            processor.run(record),
+            Err(Error::InvariantViolation(_))
+        ));
+    }
+
+    #[test]
+    fn statements() {
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,1,1,100,",
+            "deposit,2,2,50,",
+            "withdrawal,1,3,30,",
+            "transfer,2,4,20,1",
+            "dispute,1,1,,",
+            "resolve,1,1,,",
+            "dispute,1,4,,",
+            "chargeback,1,4,,"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let history = processor.accounts.history(1).unwrap();
+        let actions: Vec<&str> = history.iter().map(|event| event.action.as_str()).collect();
+        assert_eq!(
+            actions,
+            vec![
+                "deposit",
+                "withdrawal",
+                "transfer",
+                "dispute",
+                "resolve",
+                "dispute",
+                "chargeback"
+            ]
+        );
+        assert_eq!(history[2].amount, dec!(20));
+        assert_eq!(history[2].total, dec!(90));
+        assert_eq!(history[3].amount, dec!(0));
+        assert_eq!(history[3].held, dec!(100));
+        assert_eq!(history[6].total, dec!(70));
+        // The charged back transfer went back to the sender.
+        assert_eq!(
+            processor.accounts.history(2).unwrap().last().unwrap().total,
+            dec!(50)
+        );
+
+        let statement = Statement {
+            client: 1,
+            from: Some(3),
+            to: Some(4),
+            format: Format::Csv,
+        };
+        let mut out = Vec::new();
+        statement
+            .write(&processor.accounts.history(1).unwrap(), &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "position,timestamp,tx,action,currency,amount,available,held,uncleared,total\n\
+             3,,3,withdrawal,USD,-30,70,0,0,70\n\
+             4,,4,transfer,USD,20,90,0,0,90\n"
+        );
+
+        let statement = Statement {
+            format: Format::Json,
+            ..statement
+        };
+        let mut out = Vec::new();
+        statement
+            .write(&processor.accounts.history(1).unwrap(), &mut out)
+            .unwrap();
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["action"], "transfer");
+        assert_eq!(events[1]["total"], "90");
+    }
+
+    #[test]
+    fn transaction_exports() {
+        let records = records_with!(
+            "type,client,tx,amount,destination",
+            "deposit,1,1,100,",
+            "deposit,2,2,50,",
+            "transfer,2,3,20,1",
+            "dispute,1,3,,",
+            "dispute,1,1,,",
+            "dispute,2,2,,",
+            "resolve,2,2,,"
+        );
+        let mut processor = Processor::new();
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        let transactions = rows(|f| processor.accounts.transactions(&[], f));
+        let txs: Vec<u32> = transactions.iter().map(|row| row.tx).collect();
+        assert_eq!(txs, vec![1, 2, 3]);
+        assert_eq!(transactions[1].status, Status::Resolved);
+        assert_eq!(transactions[2].kind, "transfer");
+        assert_eq!(transactions[2].destination, Some(1));
+
+        let pending = rows(|f| processor.accounts.transactions(&[Status::Pending], f));
+        assert_eq!(pending.len(), 2);
+
+        // The dispute queue lists the client that raised each dispute, oldest first.
+        let disputes = rows(|f| processor.accounts.pending_disputes(f));
+        let queue: Vec<(u32, u16, u64)> = disputes
+            .iter()
+            .map(|row| (row.tx, row.client, row.position))
+            .collect();
+        assert_eq!(queue, vec![(3, 1, 4), (1, 1, 5)]);
+    }
+
+    #[test]
+    fn lock_history() {
+        let records = records!(
+            "deposit,1,1,100",
+            "deposit,1,2,50",
+            "deposit,2,3,10",
+            "dispute,1,1,",
+            "dispute,1,2,",
+            "chargeback,1,1,"
+        );
+        let mut processor = Processor::new();
+        processor.accounts.set_unlock_on_representment(true);
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        processor.accounts.lock(2, "blocked").unwrap();
+
+        let rows = processor.accounts.locked_accounts().unwrap();
+        let locks: Vec<(u16, Option<u32>, u64, &str, Decimal)> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.client,
+                    row.tx,
+                    row.position,
+                    row.reason.as_str(),
+                    row.amount,
+                )
+            })
+            .collect();
+        assert_eq!(
+            locks,
+            vec![
+                (1, Some(1), 6, "chargeback", dec!(100)),
+                (2, None, 6, "blocked", dec!(0))
+            ]
+        );
+
+        // Representing the chargeback lifts its lock but keeps it in the history.
+        processor
+            .process(records!("representment,1,1,").remove(0))
+            .unwrap();
+        let account = processor.accounts.account(1).unwrap();
+        assert!(!account.frozen());
+        assert_eq!(account.locks().len(), 1);
+        assert_eq!(account.locks()[0].lifted.map(|at| at.position), Some(7));
+        assert_eq!(processor.accounts.locked_accounts().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn opening_balances() {
+        let openings = account::opening_balances_from_reader(
+            "client,currency,available,held,total,locked\n\
+             1,USD,100,20,120,false\n\
+             1,eur,-5,0,-5,false\n\
+             2,,10,0,10,true\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut processor = Processor::new();
+        for opening in &openings {
+            processor.accounts.open(opening).unwrap();
+        }
+        processor.config.check_invariants = Some(CheckMode::Record);
+        let records = records!("withdrawal,1,1,100", "deposit,2,2,5");
+        for record in records {
+            processor.run(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(0));
+        assert_eq!(account.total(), dec!(20));
+        assert_eq!(account.total_in("EUR"), dec!(-5));
+        // Locked accounts stay locked.
+        let account = processor.accounts.account(2).unwrap();
+        assert!(account.frozen());
+        assert_eq!(account.total(), dec!(10));
+        assert!(processor.violations.is_empty());
+        assert_eq!(processor.accounts.history(1).unwrap()[0].action, "opening");
+
+        // Rows must add up, and each balance is only opened once.
+        let rows = account::opening_balances_from_reader(
+            "client,available,held,total,locked\n1,10,0,11,false\n".as_bytes(),
+        );
+        assert!(matches!(rows, Err(Error::InvalidOpening(1, _))));
+        assert!(matches!(
+            processor.accounts.open(&openings[0]),
+            Err(Error::InvalidOpening(1, _))
+        ));
+    }
+
+    #[test]
+    fn close() {
+        let dir = std::env::temp_dir().join(format!("processor-close-{}", std::process::id()));
+        let records = records!(
+            "deposit,3,6,30",
+            "dispute,3,6,",
+            "chargeback,3,6,",
+            "deposit,1,1,100",
+            "deposit,2,8,100",
+            "deposit,2,2,50",
+            "dispute,2,2,",
+            "withdrawal,1,3,10",
+            "refund,1,3,",
+            "authorize,1,4,20",
+            "deposit,1,5,5"
+        );
+        let fees = || Schedule::from_reader("type,method,value\ndispute,flat,5\n".as_bytes());
+        let mut processor = Processor::new();
+        processor.accounts.set_fees(fees().unwrap(), 0);
+        processor
+            .accounts
+            .set_dispute_window(Some(Window::Records(3)), None);
+        for record in records {
+            processor.process(record).unwrap();
+        }
+        processor.close(&dir).unwrap();
+        // Transactions that can no longer change are archived, the others carried over:
+        // deposits and withdrawals can still be refunded.
+        let txs: Vec<u32> = rows(|f| processor.accounts.transactions(&[], f))
+            .iter()
+            .map(|row| row.tx)
+            .collect();
+        assert_eq!(txs, vec![1, 2, 4, 5, 6, 8]);
+        processor.config.check_invariants = Some(CheckMode::End);
+        processor.check_invariants(None).unwrap();
+        assert!(processor.violations.is_empty());
+        let archive = std::fs::read_to_string(dir.join("archive.csv")).unwrap();
+        assert_eq!(archive.lines().count(), 2);
+        assert!(dir.join("statements").join("1.csv").exists());
+
+        // The next run starts from the balances and the carried transactions.
+        let mut processor = Processor::new();
+        processor.config.carry_forward = Some(dir.to_str().unwrap().to_string());
+        processor.config.dispute_window = Some(Window::Records(3));
+        processor.config.check_invariants = Some(CheckMode::Record);
+        processor.config.unlock_on_representment = true;
+        processor.configure().unwrap();
+        processor.accounts.set_fees(fees().unwrap(), 0);
+        // Ids of archived transactions can't be reused.
+        assert!(matches!(
+            processor.process(records!("deposit,1,3,7").remove(0)),
+            Err(Error::TxExists)
+        ));
+        let records = records!(
+            "dispute,1,5,",
+            "resolve,2,2,",
+            "refund,1,1,10",
+            "capture,1,4,",
+            "deposit,1,7,7",
+            "representment,3,6,"
+        );
+        for record in records {
+            processor.run(record).unwrap();
+        }
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(72));
+        assert_eq!(account.total(), dec!(77));
+        // The fee of the carried dispute is returned when it's resolved.
+        let account = processor.accounts.account(2).unwrap();
+        assert_eq!(account.available(), dec!(150));
+        // The house only keeps the fee of the dispute still pending.
+        assert_eq!(processor.accounts.account(0).unwrap().total(), dec!(5));
+        // The chargeback's lock is carried over and lifted by its representment.
+        let account = processor.accounts.account(3).unwrap();
+        assert_eq!(account.total(), dec!(30));
+        assert!(!account.frozen());
+        let locks = account.locks();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].tx, Some(6));
+        assert_eq!(locks[0].reason, "chargeback");
+        assert_eq!(locks[0].amount, dec!(30));
+        assert!(locks[0].lifted.is_some());
+        assert!(processor.violations.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store() {
+        let file = std::env::temp_dir().join(format!("processor-store-{}.db", std::process::id()));
+        let path = file.to_str().unwrap().to_string();
+        let schedule = || Schedule::from_reader("type,method,value\ndispute,flat,1\n".as_bytes());
+        let mut processor = Processor::new();
+        processor.config.store = Some(path.clone());
+        processor.configure().unwrap();
+        processor.accounts.set_fees(schedule().unwrap(), 0);
+        for record in records!("deposit,1,1,10", "deposit,1,2,20", "dispute,1,2,") {
+            processor.process(record).unwrap();
+        }
+        assert_eq!(processor.accounts.account(1).unwrap().total(), dec!(29));
+        drop(processor);
+
+        // The next run goes on from the store: its journal, histories, disputes, fees and positions.
+        let mut processor = Processor::new();
+        processor.config.store = Some(path.clone());
+        processor.config.check_invariants = Some(CheckMode::Record);
+        processor.configure().unwrap();
+        processor.accounts.set_fees(schedule().unwrap(), 0);
+        assert!(matches!(
+            processor.process(records!("deposit,1,1,5").remove(0)),
+            Err(Error::TxExists)
+        ));
+        for record in records!("resolve,1,2,", "withdrawal,1,3,25") {
+            processor.run(record).unwrap();
+        }
+        assert!(processor.violations.is_empty());
+        // Resolving the dispute of the previous run returns its fee.
+        let account = processor.accounts.account(1).unwrap();
+        assert_eq!(account.available(), dec!(5));
+        assert_eq!(account.total(), dec!(5));
+        assert_eq!(processor.accounts.account(0).unwrap().total(), dec!(0));
+        let positions: Vec<u64> = processor
+            .accounts
+            .history(1)
+            .unwrap()
+            .iter()
+            .map(|event| event.position)
+            .collect();
+        assert_eq!(positions, vec![1, 2, 3, 5, 6]);
+        assert_eq!(rows(|f| processor.accounts.entries(f)).len(), 5);
+        let postings = rows(|f| processor.accounts.fee_postings(f));
+        assert_eq!(postings.len(), 1);
+        assert!(postings[0].reversed);
+        drop(processor);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
 }
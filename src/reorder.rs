@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+
+use crate::error::{Error, Result};
+use crate::processor::Record;
+
+/// Reorder buffers records and releases them in timestamp order.
+/// A record is released once a record at least `window` seconds newer arrives,
+/// and records older than that are rejected as late.
+#[derive(Debug)]
+pub(crate) struct Reorder {
+    window: u64,
+    // Records keyed by timestamp and arrival, so equal timestamps keep their order.
+    buffer: BTreeMap<(u64, u64), Record>,
+    arrivals: u64,
+    latest: Option<u64>,
+}
+
+impl Reorder {
+    pub(crate) fn new(window: u64) -> Self {
+        Self {
+            window,
+            buffer: BTreeMap::new(),
+            arrivals: 0,
+            latest: None,
+        }
+    }
+
+    // Records older than this can no longer be placed in order.
+    fn watermark(&self) -> Option<u64> {
+        self.latest.map(|latest| latest.saturating_sub(self.window))
+    }
+
+    /// Adds a record and returns the records that are ready to be processed.
+    pub(crate) fn push(&mut self, record: Record) -> Result<Vec<Record>> {
+        let timestamp = record.timestamp().ok_or(Error::InvalidData)?;
+        if let Some(watermark) = self.watermark() {
+            if timestamp < watermark {
+                return Err(Error::LateRecord(record.tx()));
+            }
+        }
+
+        self.arrivals += 1;
+        self.buffer.insert((timestamp, self.arrivals), record);
+        self.latest = self.latest.max(Some(timestamp));
+
+        let mut ready = Vec::new();
+        let watermark = self.watermark().unwrap_or_default();
+        while let Some(entry) = self.buffer.first_entry() {
+            if entry.key().0 > watermark {
+                break;
+            }
+            ready.push(entry.remove());
+        }
+        Ok(ready)
+    }
+
+    /// Returns every buffered record, at the end of the input.
+    pub(crate) fn flush(&mut self) -> Vec<Record> {
+        std::mem::take(&mut self.buffer).into_values().collect()
+    }
+}
@@ -0,0 +1,250 @@
+use csv::Reader as CsvReader;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+
+use crate::error::{Error, Result};
+use crate::processor::{Record, Type};
+
+/// Decision of a rule about a record.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Verdict {
+    Allow,
+    Reject(String),
+    Flag(String),
+}
+
+/// What a built-in rule does with a record that matches it.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Action {
+    Flag,
+    Reject,
+}
+
+impl Action {
+    fn verdict(self, reason: String) -> Verdict {
+        match self {
+            Action::Flag => Verdict::Flag(reason),
+            Action::Reject => Verdict::Reject(reason),
+        }
+    }
+}
+
+/// Activity of a client as seen by the rules.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct History {
+    pub(crate) deposits: u32,
+    pub(crate) disputes: u32,
+    // Position and amount of the latest deposit.
+    pub(crate) last_deposit: Option<(u64, Decimal)>,
+}
+
+/// Rule inspects a record before it is applied.
+pub(crate) trait Rule {
+    fn name(&self) -> &str;
+
+    fn check(&self, record: &Record, position: u64, history: &History) -> Verdict;
+}
+
+/// Record flagged by a rule.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Flag {
+    pub(crate) position: u64,
+    pub(crate) client: u16,
+    pub(crate) tx: u32,
+    pub(crate) rule: String,
+    pub(crate) reason: String,
+}
+
+/// Engine runs the rules on every record and keeps the history they look at.
+#[derive(Default)]
+pub(crate) struct Engine {
+    rules: Vec<Box<dyn Rule>>,
+    history: HashMap<u16, History>,
+    flags: Vec<Flag>,
+    // Flags of the record being checked, kept once it's applied.
+    staged: Vec<Flag>,
+}
+
+impl Engine {
+    pub(crate) fn add_rule(&mut self, rule: Box<dyn Rule>) {
+        self.rules.push(rule);
+    }
+
+    pub(crate) fn flags(&self) -> &[Flag] {
+        &self.flags
+    }
+
+    /// Runs every rule on the record.
+    /// The record is rejected by the first rule that rejects it.
+    pub(crate) fn check(&mut self, record: &Record, position: u64) -> Result<()> {
+        self.staged.clear();
+        let history = self
+            .history
+            .get(&record.client())
+            .cloned()
+            .unwrap_or_default();
+        for rule in &self.rules {
+            match rule.check(record, position, &history) {
+                Verdict::Allow => {}
+                Verdict::Reject(reason) => {
+                    return Err(Error::Rejected(rule.name().to_string(), reason))
+                }
+                Verdict::Flag(reason) => {
+                    self.staged.push(Flag {
+                        position,
+                        client: record.client(),
+                        tx: record.tx(),
+                        rule: rule.name().to_string(),
+                        reason,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the history with a record that was applied, and keeps its flags.
+    pub(crate) fn record(&mut self, record: &Record, position: u64) {
+        for flag in self.staged.drain(..) {
+            log::warn!("tx {} flagged by {}: {}", flag.tx, flag.rule, flag.reason);
+            self.flags.push(flag);
+        }
+        let history = self.history.entry(record.client()).or_default();
+        match record.ty() {
+            Type::Deposit => {
+                history.deposits += 1;
+                history.last_deposit = record.amount().map(|amount| (position, amount));
+            }
+            Type::Dispute => history.disputes += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Matches disputes from clients that already raised `max` disputes.
+pub(crate) struct RepeatedDisputes {
+    pub(crate) max: u32,
+    pub(crate) action: Action,
+}
+
+impl Rule for RepeatedDisputes {
+    fn name(&self) -> &str {
+        "repeated-disputes"
+    }
+
+    fn check(&self, record: &Record, _: u64, history: &History) -> Verdict {
+        match record.ty() {
+            Type::Dispute if history.disputes >= self.max => self.action.verdict(format!(
+                "client raised {} disputes before",
+                history.disputes
+            )),
+            _ => Verdict::Allow,
+        }
+    }
+}
+
+/// Matches withdrawals within `window` records of a deposit of at least `min_amount`.
+pub(crate) struct DepositThenWithdrawal {
+    pub(crate) min_amount: Decimal,
+    pub(crate) window: u64,
+    pub(crate) action: Action,
+}
+
+impl Rule for DepositThenWithdrawal {
+    fn name(&self) -> &str {
+        "deposit-withdrawal"
+    }
+
+    fn check(&self, record: &Record, position: u64, history: &History) -> Verdict {
+        match (record.ty(), history.last_deposit) {
+            (Type::Withdrawal, Some((at, amount)))
+                if amount >= self.min_amount && position - at <= self.window =>
+            {
+                self.action.verdict(format!(
+                    "withdrawal {} records after a deposit of {}",
+                    position - at,
+                    amount
+                ))
+            }
+            _ => Verdict::Allow,
+        }
+    }
+}
+
+/// Matches disputes that take the client's ratio of disputes to deposits above `threshold`.
+pub(crate) struct DisputeRatio {
+    pub(crate) threshold: Decimal,
+    pub(crate) action: Action,
+}
+
+impl Rule for DisputeRatio {
+    fn name(&self) -> &str {
+        "dispute-ratio"
+    }
+
+    fn check(&self, record: &Record, _: u64, history: &History) -> Verdict {
+        if !matches!(record.ty(), Type::Dispute) || history.deposits == 0 {
+            return Verdict::Allow;
+        }
+        let ratio = Decimal::from(history.disputes + 1) / Decimal::from(history.deposits);
+        if ratio > self.threshold {
+            self.action.verdict(format!(
+                "dispute ratio {} above {}",
+                ratio.round_dp(4),
+                self.threshold
+            ))
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+/// Row of the risk rules file, configuring one of the built-in rules.
+#[derive(Debug, Deserialize)]
+struct RuleConfig {
+    rule: String,
+    action: Action,
+    limit: Decimal,
+    #[serde(default)]
+    window: Option<u64>,
+}
+
+/// Loads the built-in rules listed in a file.
+pub(crate) fn rules_from_path(file: &str) -> Result<Vec<Box<dyn Rule>>> {
+    let rdr = CsvReader::from_path(file).map_err(|_| Error::InvalidData)?;
+    rules_from_csv(rdr)
+}
+
+#[cfg(test)]
+pub(crate) fn rules_from_reader<R: io::Read>(rdr: R) -> Result<Vec<Box<dyn Rule>>> {
+    rules_from_csv(CsvReader::from_reader(rdr))
+}
+
+fn rules_from_csv<R: io::Read>(mut rdr: CsvReader<R>) -> Result<Vec<Box<dyn Rule>>> {
+    let mut rules: Vec<Box<dyn Rule>> = Vec::new();
+    for result in rdr.deserialize() {
+        let config: RuleConfig = result.map_err(|_| Error::InvalidData)?;
+        let action = config.action;
+        let rule: Box<dyn Rule> = match config.rule.as_str() {
+            "repeated-disputes" => Box::new(RepeatedDisputes {
+                max: config.limit.try_into().map_err(|_| Error::InvalidData)?,
+                action,
+            }),
+            "deposit-withdrawal" => Box::new(DepositThenWithdrawal {
+                min_amount: config.limit,
+                window: config.window.ok_or(Error::InvalidData)?,
+                action,
+            }),
+            "dispute-ratio" => Box::new(DisputeRatio {
+                threshold: config.limit,
+                action,
+            }),
+            _ => return Err(Error::InvalidData),
+        };
+        rules.push(rule);
+    }
+    Ok(rules)
+}
@@ -0,0 +1,421 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::account::{Account, Transaction};
+use crate::audit::AuditEntry;
+use crate::error::{Error, Result};
+use crate::fees::FeePosting;
+use crate::ledger::{Book, Entry};
+use crate::statement::Event;
+use crate::store::{Store, Visit};
+
+// Migrations of the schema, in order. The user version of a database is the number of
+// migrations applied to it. Rows are kept as json, with the columns they're looked up by
+// generated from it.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE accounts (
+        client INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE transactions (
+        tx INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );",
+    "ALTER TABLE transactions ADD COLUMN disputed INTEGER AS (
+        CASE WHEN json_extract(data, '$.status') = 'pending'
+        THEN json_extract(data, '$.dispute[0].position') END
+    );
+    ALTER TABLE transactions ADD COLUMN cleared INTEGER AS (json_extract(data, '$.cleared'));
+    ALTER TABLE transactions ADD COLUMN position INTEGER AS (
+        json_extract(data, '$.created.position')
+    );
+    ALTER TABLE transactions ADD COLUMN timestamp INTEGER AS (
+        json_extract(data, '$.created.timestamp')
+    );
+    CREATE INDEX transactions_pending ON transactions (disputed, tx)
+        WHERE disputed IS NOT NULL;
+    CREATE INDEX transactions_uncleared ON transactions (position, timestamp, tx)
+        WHERE NOT cleared;
+    CREATE TABLE archived (
+        tx INTEGER PRIMARY KEY
+    );
+    CREATE TABLE entries (
+        id INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE books (
+        book TEXT NOT NULL,
+        currency TEXT NOT NULL,
+        -- Client of a client book, system books have none.
+        client INTEGER,
+        data TEXT NOT NULL,
+        PRIMARY KEY (book, currency)
+    );
+    CREATE INDEX books_system ON books (currency) WHERE client IS NULL;
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX events_client ON events (client);
+    CREATE TABLE postings (
+        tx INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE audit (
+        id INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE opening (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (client, currency)
+    );
+    CREATE TABLE state (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );",
+];
+
+/// Store that keeps the accounts and transactions in a SQLite database.
+#[derive(Debug)]
+pub(crate) struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database of a file, creating it if it doesn't exist.
+    pub(crate) fn open(file: &str) -> Result<Self> {
+        let conn = Connection::open(file).map_err(error)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(error)?;
+        conn.pragma_update(None, "synchronous", "NORMAL")
+            .map_err(error)?;
+        Self::migrate(conn)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> Result<Self> {
+        Self::migrate(Connection::open_in_memory().map_err(error)?)
+    }
+
+    // Applies the migrations the database is missing.
+    fn migrate(mut conn: Connection) -> Result<Self> {
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(error)?;
+        if version > MIGRATIONS.len() {
+            return Err(Error::Store(format!(
+                "schema version {} is newer than {}",
+                version,
+                MIGRATIONS.len()
+            )));
+        }
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction().map_err(error)?;
+            tx.execute_batch(migration).map_err(error)?;
+            tx.pragma_update(None, "user_version", applied + 1)
+                .map_err(error)?;
+            tx.commit().map_err(error)?;
+        }
+        Ok(Self { conn })
+    }
+
+    fn get<T: DeserializeOwned>(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Option<T>> {
+        let data: Option<String> = self
+            .conn
+            .prepare_cached(sql)
+            .and_then(|mut stmt| stmt.query_row(params, |row| row.get(0)).optional())
+            .map_err(error)?;
+        data.map(|data| decode(&data)).transpose()
+    }
+
+    fn all<T: DeserializeOwned>(&self, sql: &str) -> Result<Vec<T>> {
+        let mut rows = Vec::new();
+        self.scan(sql, [], &mut |row| {
+            rows.push(row);
+            Ok(())
+        })?;
+        Ok(rows)
+    }
+
+    // Visits the rows of a query one at a time, without loading them all.
+    fn scan<T: DeserializeOwned>(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+        f: Visit<T>,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(sql).map_err(error)?;
+        let mut rows = stmt.query(params).map_err(error)?;
+        while let Some(row) = rows.next().map_err(error)? {
+            f(decode(&row.get::<_, String>(0).map_err(error)?)?)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&self, sql: &str, params: impl rusqlite::Params) -> Result<()> {
+        self.conn
+            .prepare_cached(sql)
+            .and_then(|mut stmt| stmt.execute(params))
+            .map_err(error)?;
+        Ok(())
+    }
+}
+
+impl Store for SqliteStore {
+    fn account(&self, client: u16) -> Result<Option<Account>> {
+        self.get("SELECT data FROM accounts WHERE client = ?1", [client])
+    }
+
+    fn put_account(&mut self, account: Account) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO accounts (client, data) VALUES (?1, ?2)",
+            params![account.client(), encode(&account).map_err(error)?],
+        )
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>> {
+        self.all("SELECT data FROM accounts")
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>> {
+        self.get("SELECT data FROM transactions WHERE tx = ?1", [tx])
+    }
+
+    fn put_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO transactions (tx, data) VALUES (?1, ?2)",
+            params![transaction.id(), encode(&transaction).map_err(error)?],
+        )
+    }
+
+    fn remove_transaction(&mut self, tx: u32) -> Result<()> {
+        self.execute("DELETE FROM transactions WHERE tx = ?1", [tx])
+    }
+
+    fn scan_transactions(&self, f: Visit<Transaction>) -> Result<()> {
+        self.scan("SELECT data FROM transactions ORDER BY tx", [], f)
+    }
+
+    fn scan_pending(&self, f: Visit<Transaction>) -> Result<()> {
+        self.scan(
+            "SELECT data FROM transactions WHERE disputed IS NOT NULL ORDER BY disputed, tx",
+            [],
+            f,
+        )
+    }
+
+    fn oldest_uncleared(&self) -> Result<Option<Transaction>> {
+        let mut oldest = None;
+        self.scan(
+            "SELECT data FROM transactions WHERE NOT cleared
+            ORDER BY position, timestamp, tx LIMIT 1",
+            [],
+            &mut |transaction| {
+                oldest = Some(transaction);
+                Ok(())
+            },
+        )?;
+        Ok(oldest)
+    }
+
+    fn put_archived(&mut self, tx: u32) -> Result<()> {
+        self.execute("INSERT OR IGNORE INTO archived (tx) VALUES (?1)", [tx])
+    }
+
+    fn is_archived(&self, tx: u32) -> Result<bool> {
+        self.conn
+            .prepare_cached("SELECT 1 FROM archived WHERE tx = ?1")
+            .and_then(|mut stmt| stmt.exists([tx]))
+            .map_err(error)
+    }
+
+    fn scan_archived(&self, f: Visit<u32>) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT tx FROM archived ORDER BY tx")
+            .map_err(error)?;
+        let mut rows = stmt.query([]).map_err(error)?;
+        while let Some(row) = rows.next().map_err(error)? {
+            f(row.get(0).map_err(error)?)?;
+        }
+        Ok(())
+    }
+
+    fn put_entry(&mut self, entry: Entry) -> Result<()> {
+        self.execute(
+            "INSERT INTO entries (data) VALUES (?1)",
+            [encode(&entry).map_err(error)?],
+        )
+    }
+
+    fn scan_entries(&self, f: Visit<Entry>) -> Result<()> {
+        self.scan("SELECT data FROM entries ORDER BY id", [], f)
+    }
+
+    fn balance(&self, book: Book, currency: &str) -> Result<Decimal> {
+        let balance = self.get(
+            "SELECT data FROM books WHERE book = ?1 AND currency = ?2",
+            params![encode(&book).map_err(error)?, currency],
+        )?;
+        Ok(balance.unwrap_or_default())
+    }
+
+    fn put_balance(&mut self, book: Book, currency: &str, balance: Decimal) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO books (book, currency, client, data) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                encode(&book).map_err(error)?,
+                currency,
+                book.client(),
+                encode(&balance).map_err(error)?
+            ],
+        )
+    }
+
+    fn system_balances(&self, currency: &str) -> Result<Vec<Decimal>> {
+        let mut balances = Vec::new();
+        self.scan(
+            "SELECT data FROM books WHERE currency = ?1 AND client IS NULL",
+            [currency],
+            &mut |balance| {
+                balances.push(balance);
+                Ok(())
+            },
+        )?;
+        Ok(balances)
+    }
+
+    fn put_event(&mut self, client: u16, event: Event) -> Result<()> {
+        self.execute(
+            "INSERT INTO events (client, data) VALUES (?1, ?2)",
+            params![client, encode(&event).map_err(error)?],
+        )
+    }
+
+    fn history(&self, client: u16) -> Result<Vec<Event>> {
+        let mut history = Vec::new();
+        self.scan(
+            "SELECT data FROM events WHERE client = ?1 ORDER BY id",
+            [client],
+            &mut |event| {
+                history.push(event);
+                Ok(())
+            },
+        )?;
+        Ok(history)
+    }
+
+    fn postings(&self, tx: u32) -> Result<Vec<FeePosting>> {
+        let postings = self.get("SELECT data FROM postings WHERE tx = ?1", [tx])?;
+        Ok(postings.unwrap_or_default())
+    }
+
+    fn put_postings(&mut self, tx: u32, postings: Vec<FeePosting>) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO postings (tx, data) VALUES (?1, ?2)",
+            params![tx, encode(&postings).map_err(error)?],
+        )
+    }
+
+    fn scan_postings(&self, f: Visit<FeePosting>) -> Result<()> {
+        self.scan(
+            "SELECT data FROM postings ORDER BY tx",
+            [],
+            &mut |postings: Vec<FeePosting>| postings.into_iter().try_for_each(&mut *f),
+        )
+    }
+
+    fn put_audit(&mut self, entry: AuditEntry) -> Result<()> {
+        self.execute(
+            "INSERT INTO audit (data) VALUES (?1)",
+            [encode(&entry).map_err(error)?],
+        )
+    }
+
+    fn scan_audit(&self, f: Visit<AuditEntry>) -> Result<()> {
+        self.scan("SELECT data FROM audit ORDER BY id", [], f)
+    }
+
+    fn put_opening(&mut self, key: (u16, String), funds: (Decimal, Decimal)) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO opening (client, currency, data) VALUES (?1, ?2, ?3)",
+            params![key.0, key.1, encode(&funds).map_err(error)?],
+        )
+    }
+
+    fn opening(&self, key: &(u16, String)) -> Result<Option<(Decimal, Decimal)>> {
+        self.get(
+            "SELECT data FROM opening WHERE client = ?1 AND currency = ?2",
+            params![key.0, key.1],
+        )
+    }
+
+    fn scan_opening(&self, f: Visit<((u16, String), (Decimal, Decimal))>) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT client, currency, data FROM opening")
+            .map_err(error)?;
+        let mut rows = stmt.query([]).map_err(error)?;
+        while let Some(row) = rows.next().map_err(error)? {
+            let key = (row.get(0).map_err(error)?, row.get(1).map_err(error)?);
+            f((key, decode(&row.get::<_, String>(2).map_err(error)?)?))?;
+        }
+        Ok(())
+    }
+
+    fn put_position(&mut self, position: u64) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO state (key, value) VALUES ('position', ?1)",
+            [position],
+        )
+    }
+
+    fn position(&self) -> Result<u64> {
+        let position: Option<u64> = self
+            .conn
+            .query_row(
+                "SELECT value FROM state WHERE key = 'position'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(error)?;
+        Ok(position.unwrap_or_default())
+    }
+
+    // The outermost savepoint is a SQL transaction, committed when it's released.
+    fn begin(&mut self) -> Result<()> {
+        self.conn.execute_batch("SAVEPOINT changes").map_err(error)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.conn.execute_batch("RELEASE changes").map_err(error)
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        self.conn
+            .execute_batch("ROLLBACK TO changes; RELEASE changes")
+            .map_err(error)
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))
+}
+
+fn decode<T: DeserializeOwned>(data: &str) -> Result<T> {
+    serde_json::from_str(data).map_err(|e| Error::Store(e.to_string()))
+}
+
+fn error(e: rusqlite::Error) -> Error {
+    Error::Store(e.to_string())
+}
@@ -0,0 +1,62 @@
+use csv::Writer as CsvWriter;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+use crate::error::{Error, Result};
+
+/// Event of a client's history, with the balance it left in its currency.
+/// The amount is the change of the total, so it's zero for funds moved between available and held.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Event {
+    pub(crate) position: u64,
+    pub(crate) timestamp: Option<u64>,
+    pub(crate) tx: Option<u32>,
+    pub(crate) action: String,
+    pub(crate) currency: String,
+    pub(crate) amount: Decimal,
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    pub(crate) uncleared: Decimal,
+    pub(crate) total: Decimal,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+/// Statement of a client's history for a range of record positions.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Statement {
+    pub(crate) client: u16,
+    pub(crate) from: Option<u64>,
+    pub(crate) to: Option<u64>,
+    pub(crate) format: Format,
+}
+
+impl Statement {
+    /// Writes the events of the history that fall within the range.
+    pub(crate) fn write<W: Write>(&self, history: &[Event], out: W) -> Result<()> {
+        let events = history.iter().filter(|event| {
+            self.from.is_none_or(|from| event.position >= from)
+                && self.to.is_none_or(|to| event.position <= to)
+        });
+        match self.format {
+            Format::Csv => {
+                let mut wtr = CsvWriter::from_writer(out);
+                for event in events {
+                    wtr.serialize(event).map_err(|_| Error::InvalidData)?;
+                }
+                wtr.flush().map_err(|_| Error::InvalidData)?;
+            }
+            Format::Json => {
+                let events: Vec<&Event> = events.collect();
+                serde_json::to_writer_pretty(out, &events).map_err(|_| Error::InvalidData)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use crate::account::{Account, Transaction};
+use crate::audit::AuditEntry;
+use crate::error::Result;
+use crate::fees::FeePosting;
+use crate::ledger::{Book, Entry};
+use crate::statement::Event;
+
+/// Callback that a scan of the store visits rows with, one at a time.
+pub(crate) type Visit<'a, T> = &'a mut dyn FnMut(T) -> Result<()>;
+
+/// Store keeps the accounts and transactions of `Accounts`, the ledger, the histories
+/// of the clients and what a later run needs to go on from them.
+/// Lookups return copies, which are only changed in the store when they're put back.
+/// Everything that grows with the transactions is scanned rather than returned at once.
+pub(crate) trait Store: fmt::Debug {
+    fn account(&self, client: u16) -> Result<Option<Account>>;
+
+    fn put_account(&mut self, account: Account) -> Result<()>;
+
+    /// Every account, in no particular order.
+    fn accounts(&self) -> Result<Vec<Account>>;
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>>;
+
+    fn put_transaction(&mut self, transaction: Transaction) -> Result<()>;
+
+    fn remove_transaction(&mut self, tx: u32) -> Result<()>;
+
+    /// Visits every transaction, in tx order.
+    fn scan_transactions(&self, f: Visit<Transaction>) -> Result<()>;
+
+    /// Visits the transactions of the pending disputes, in the order they were raised.
+    fn scan_pending(&self, f: Visit<Transaction>) -> Result<()>;
+
+    /// The deposit that has been uncleared the longest.
+    fn oldest_uncleared(&self) -> Result<Option<Transaction>>;
+
+    /// Keeps the id of an archived transaction, which can't be reused.
+    fn put_archived(&mut self, tx: u32) -> Result<()>;
+
+    fn is_archived(&self, tx: u32) -> Result<bool>;
+
+    /// Visits the ids of every archived transaction, in order.
+    fn scan_archived(&self, f: Visit<u32>) -> Result<()>;
+
+    fn put_entry(&mut self, entry: Entry) -> Result<()>;
+
+    /// Visits the journal, in the order its entries were put.
+    fn scan_entries(&self, f: Visit<Entry>) -> Result<()>;
+
+    /// Balance of a book of the ledger in a currency.
+    fn balance(&self, book: Book, currency: &str) -> Result<Decimal>;
+
+    fn put_balance(&mut self, book: Book, currency: &str, balance: Decimal) -> Result<()>;
+
+    /// Balances of the system books in a currency.
+    fn system_balances(&self, currency: &str) -> Result<Vec<Decimal>>;
+
+    fn put_event(&mut self, client: u16, event: Event) -> Result<()>;
+
+    /// History of a client, in the order its events were put.
+    fn history(&self, client: u16) -> Result<Vec<Event>>;
+
+    /// Fee postings of a transaction, in the order they were charged.
+    fn postings(&self, tx: u32) -> Result<Vec<FeePosting>>;
+
+    fn put_postings(&mut self, tx: u32, postings: Vec<FeePosting>) -> Result<()>;
+
+    /// Visits every fee posting, in tx order.
+    fn scan_postings(&self, f: Visit<FeePosting>) -> Result<()>;
+
+    fn put_audit(&mut self, entry: AuditEntry) -> Result<()>;
+
+    /// Visits the audit trail, in the order its entries were put.
+    fn scan_audit(&self, f: Visit<AuditEntry>) -> Result<()>;
+
+    /// Held and total funds set aside for a client and currency.
+    fn put_opening(&mut self, key: (u16, String), funds: (Decimal, Decimal)) -> Result<()>;
+
+    fn opening(&self, key: &(u16, String)) -> Result<Option<(Decimal, Decimal)>>;
+
+    /// Visits the funds set aside for every client and currency.
+    fn scan_opening(&self, f: Visit<((u16, String), (Decimal, Decimal))>) -> Result<()>;
+
+    /// Position of the last record processed.
+    fn put_position(&mut self, position: u64) -> Result<()>;
+
+    fn position(&self) -> Result<u64>;
+
+    /// Starts a savepoint within those already started.
+    /// Changes are kept for good once the outermost savepoint is committed.
+    fn begin(&mut self) -> Result<()>;
+
+    /// Keeps the changes made since the last savepoint started.
+    fn commit(&mut self) -> Result<()>;
+
+    /// Undoes the changes made since the last savepoint started.
+    fn rollback(&mut self) -> Result<()>;
+}
+
+/// Opens the store of a database file.
+#[cfg(feature = "sqlite")]
+pub(crate) fn open(file: &str) -> Result<Box<dyn Store>> {
+    Ok(Box::new(crate::sqlite::SqliteStore::open(file)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub(crate) fn open(file: &str) -> Result<Box<dyn Store>> {
+    Err(crate::error::Error::InvalidConfig(format!(
+        "can't open store {} without the sqlite feature",
+        file
+    )))
+}
+
+/// Store that keeps everything in memory, the default.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore {
+    accounts: HashMap<u16, Account>,
+    transactions: BTreeMap<u32, Transaction>,
+    // Pending disputes by when they were raised.
+    pending: BTreeSet<(u64, u32)>,
+    // Uncleared deposits by when they were made.
+    uncleared: BTreeSet<(u64, Option<u64>, u32)>,
+    archived: BTreeSet<u32>,
+    entries: Vec<Entry>,
+    balances: HashMap<(Book, String), Decimal>,
+    history: HashMap<u16, Vec<Event>>,
+    postings: BTreeMap<u32, Vec<FeePosting>>,
+    audit: Vec<AuditEntry>,
+    opening: BTreeMap<(u16, String), (Decimal, Decimal)>,
+    position: u64,
+    // What undoes the changes made since each savepoint, the innermost last.
+    savepoints: Vec<Vec<Undo>>,
+}
+
+// Change undone when its savepoint is rolled back, by putting back what it replaced.
+#[derive(Debug)]
+enum Undo {
+    Account(u16, Option<Account>),
+    Transaction(u32, Option<Transaction>),
+    Archived(u32),
+    Entry,
+    Balance((Book, String), Option<Decimal>),
+    Event(u16),
+    Postings(u32, Option<Vec<FeePosting>>),
+    Audit,
+    Opening((u16, String), Option<(Decimal, Decimal)>),
+    Position(u64),
+}
+
+impl MemoryStore {
+    fn log(&mut self, undo: Undo) {
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint.push(undo);
+        }
+    }
+
+    // Puts or removes a transaction, keeping the pending and uncleared ones indexed.
+    fn set_transaction(
+        &mut self,
+        tx: u32,
+        transaction: Option<Transaction>,
+    ) -> Option<Transaction> {
+        let previous = match transaction {
+            Some(transaction) => {
+                if let Some(position) = transaction.disputed_at() {
+                    self.pending.insert((position, tx));
+                }
+                if let Some(since) = transaction.uncleared_since() {
+                    self.uncleared.insert((since.position, since.timestamp, tx));
+                }
+                self.transactions.insert(tx, transaction)
+            }
+            None => self.transactions.remove(&tx),
+        };
+        if let Some(previous) = &previous {
+            let current = self.transactions.get(&tx);
+            if let Some(position) = previous.disputed_at() {
+                if current.and_then(Transaction::disputed_at) != Some(position) {
+                    self.pending.remove(&(position, tx));
+                }
+            }
+            if let Some(since) = previous.uncleared_since() {
+                if current.and_then(Transaction::uncleared_since) != Some(since) {
+                    self.uncleared
+                        .remove(&(since.position, since.timestamp, tx));
+                }
+            }
+        }
+        previous
+    }
+
+    fn undo(&mut self, undo: Undo) {
+        match undo {
+            Undo::Account(client, Some(account)) => {
+                self.accounts.insert(client, account);
+            }
+            Undo::Account(client, None) => {
+                self.accounts.remove(&client);
+            }
+            Undo::Transaction(tx, transaction) => {
+                self.set_transaction(tx, transaction);
+            }
+            Undo::Archived(tx) => {
+                self.archived.remove(&tx);
+            }
+            Undo::Entry => {
+                self.entries.pop();
+            }
+            Undo::Balance(key, Some(balance)) => {
+                self.balances.insert(key, balance);
+            }
+            Undo::Balance(key, None) => {
+                self.balances.remove(&key);
+            }
+            Undo::Event(client) => {
+                if let Some(history) = self.history.get_mut(&client) {
+                    history.pop();
+                }
+            }
+            Undo::Postings(tx, Some(postings)) => {
+                self.postings.insert(tx, postings);
+            }
+            Undo::Postings(tx, None) => {
+                self.postings.remove(&tx);
+            }
+            Undo::Audit => {
+                self.audit.pop();
+            }
+            Undo::Opening(key, Some(funds)) => {
+                self.opening.insert(key, funds);
+            }
+            Undo::Opening(key, None) => {
+                self.opening.remove(&key);
+            }
+            Undo::Position(position) => self.position = position,
+        }
+    }
+}
+
+impl Store for MemoryStore {
+    fn account(&self, client: u16) -> Result<Option<Account>> {
+        Ok(self.accounts.get(&client).cloned())
+    }
+
+    fn put_account(&mut self, account: Account) -> Result<()> {
+        let client = account.client();
+        let previous = self.accounts.insert(client, account);
+        self.log(Undo::Account(client, previous));
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>> {
+        Ok(self.accounts.values().cloned().collect())
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>> {
+        Ok(self.transactions.get(&tx).cloned())
+    }
+
+    fn put_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        let tx = transaction.id();
+        let previous = self.set_transaction(tx, Some(transaction));
+        self.log(Undo::Transaction(tx, previous));
+        Ok(())
+    }
+
+    fn remove_transaction(&mut self, tx: u32) -> Result<()> {
+        let previous = self.set_transaction(tx, None);
+        self.log(Undo::Transaction(tx, previous));
+        Ok(())
+    }
+
+    fn scan_transactions(&self, f: Visit<Transaction>) -> Result<()> {
+        self.transactions
+            .values()
+            .try_for_each(|transaction| f(transaction.clone()))
+    }
+
+    fn scan_pending(&self, f: Visit<Transaction>) -> Result<()> {
+        self.pending
+            .iter()
+            .try_for_each(|(_, tx)| f(self.transactions[tx].clone()))
+    }
+
+    fn oldest_uncleared(&self) -> Result<Option<Transaction>> {
+        let oldest = self.uncleared.first();
+        Ok(oldest.map(|(_, _, tx)| self.transactions[tx].clone()))
+    }
+
+    fn put_archived(&mut self, tx: u32) -> Result<()> {
+        if self.archived.insert(tx) {
+            self.log(Undo::Archived(tx));
+        }
+        Ok(())
+    }
+
+    fn is_archived(&self, tx: u32) -> Result<bool> {
+        Ok(self.archived.contains(&tx))
+    }
+
+    fn scan_archived(&self, f: Visit<u32>) -> Result<()> {
+        self.archived.iter().try_for_each(|tx| f(*tx))
+    }
+
+    fn put_entry(&mut self, entry: Entry) -> Result<()> {
+        self.entries.push(entry);
+        self.log(Undo::Entry);
+        Ok(())
+    }
+
+    fn scan_entries(&self, f: Visit<Entry>) -> Result<()> {
+        self.entries.iter().try_for_each(|entry| f(entry.clone()))
+    }
+
+    fn balance(&self, book: Book, currency: &str) -> Result<Decimal> {
+        let key = (book, currency.to_string());
+        Ok(self.balances.get(&key).copied().unwrap_or_default())
+    }
+
+    fn put_balance(&mut self, book: Book, currency: &str, balance: Decimal) -> Result<()> {
+        let key = (book, currency.to_string());
+        let previous = self.balances.insert(key.clone(), balance);
+        self.log(Undo::Balance(key, previous));
+        Ok(())
+    }
+
+    fn system_balances(&self, currency: &str) -> Result<Vec<Decimal>> {
+        let balances = self
+            .balances
+            .iter()
+            .filter(|((book, code), _)| book.client().is_none() && code == currency)
+            .map(|(_, balance)| *balance)
+            .collect();
+        Ok(balances)
+    }
+
+    fn put_event(&mut self, client: u16, event: Event) -> Result<()> {
+        self.history.entry(client).or_default().push(event);
+        self.log(Undo::Event(client));
+        Ok(())
+    }
+
+    fn history(&self, client: u16) -> Result<Vec<Event>> {
+        Ok(self.history.get(&client).cloned().unwrap_or_default())
+    }
+
+    fn postings(&self, tx: u32) -> Result<Vec<FeePosting>> {
+        Ok(self.postings.get(&tx).cloned().unwrap_or_default())
+    }
+
+    fn put_postings(&mut self, tx: u32, postings: Vec<FeePosting>) -> Result<()> {
+        let previous = self.postings.insert(tx, postings);
+        self.log(Undo::Postings(tx, previous));
+        Ok(())
+    }
+
+    fn scan_postings(&self, f: Visit<FeePosting>) -> Result<()> {
+        self.postings
+            .values()
+            .flatten()
+            .try_for_each(|posting| f(posting.clone()))
+    }
+
+    fn put_audit(&mut self, entry: AuditEntry) -> Result<()> {
+        self.audit.push(entry);
+        self.log(Undo::Audit);
+        Ok(())
+    }
+
+    fn scan_audit(&self, f: Visit<AuditEntry>) -> Result<()> {
+        self.audit.iter().try_for_each(|entry| f(entry.clone()))
+    }
+
+    fn put_opening(&mut self, key: (u16, String), funds: (Decimal, Decimal)) -> Result<()> {
+        let previous = self.opening.insert(key.clone(), funds);
+        self.log(Undo::Opening(key, previous));
+        Ok(())
+    }
+
+    fn opening(&self, key: &(u16, String)) -> Result<Option<(Decimal, Decimal)>> {
+        Ok(self.opening.get(key).copied())
+    }
+
+    fn scan_opening(&self, f: Visit<((u16, String), (Decimal, Decimal))>) -> Result<()> {
+        self.opening
+            .iter()
+            .try_for_each(|(key, funds)| f((key.clone(), *funds)))
+    }
+
+    fn put_position(&mut self, position: u64) -> Result<()> {
+        let previous = std::mem::replace(&mut self.position, position);
+        self.log(Undo::Position(previous));
+        Ok(())
+    }
+
+    fn position(&self) -> Result<u64> {
+        Ok(self.position)
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        self.savepoints.push(Vec::new());
+        Ok(())
+    }
+
+    // Changes kept by an inner savepoint are still undone if an outer one is rolled back.
+    fn commit(&mut self) -> Result<()> {
+        if let Some(mut changes) = self.savepoints.pop() {
+            if let Some(outer) = self.savepoints.last_mut() {
+                outer.append(&mut changes);
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        for undo in self.savepoints.pop().unwrap_or_default().into_iter().rev() {
+            self.undo(undo);
+        }
+        Ok(())
+    }
+}